@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+fn sixteen() -> u32 {
+    16
+}
+
+fn threshold() -> f64 {
+    0.01
+}
+
+// Stops sampling a pixel once it has taken at least min_samples and the 95%
+// confidence interval of its brightness, as displayed, is narrower than
+// threshold. The scene's samples_per_pixel is the most any pixel will take.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdaptiveSampling {
+    #[serde(default = "sixteen")]
+    pub min_samples: u32,
+    // Error in display brightness, from 0 to 1, that is accepted.
+    #[serde(default = "threshold")]
+    pub threshold: f64,
+    // If provided, an image of how many samples each pixel took is written
+    // here. PNG images are scaled so that white is samples_per_pixel, while
+    // .exr and .hdr images hold the counts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_count_image: Option<String>,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdaptiveSampling {
+    pub fn new() -> AdaptiveSampling {
+        AdaptiveSampling {
+            min_samples: sixteen(),
+            threshold: threshold(),
+            sample_count_image: None,
+        }
+    }
+
+    // Whether a pixel with these luminance statistics needs no more samples.
+    pub fn converged(&self, stats: &PixelStats) -> bool {
+        stats.count >= self.min_samples.max(2) && stats.display_error() <= self.threshold
+    }
+}
+
+// Running mean and variance of a pixel's luminance samples, using Welford's
+// algorithm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelStats {
+    pub count: u32,
+    pub mean: f64,
+    // Sum of squared differences from the mean.
+    pub m2: f64,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, luminance: f64) {
+        self.count += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            self.m2 / (self.count - 1) as f64
+        }
+    }
+
+    // Half width of the 95% confidence interval of the mean, after the
+    // square root gamma applied to the output. Dark pixels are not allowed
+    // to demand unlimited samples.
+    pub fn display_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let standard_error = (self.variance() / self.count as f64).sqrt();
+        1.96 * standard_error / (2.0 * self.mean.max(0.0025).sqrt())
+    }
+}
+
+#[test]
+fn test_pixel_stats() {
+    let mut stats = PixelStats::default();
+    for value in [0.2, 0.4, 0.6, 0.8] {
+        stats.add(value);
+    }
+    assert_eq!(stats.count, 4);
+    assert_approx_eq!(stats.mean, 0.5);
+    assert_approx_eq!(stats.variance(), 0.2 / 3.0);
+
+    let adaptive = AdaptiveSampling::new();
+    assert!(!adaptive.converged(&stats));
+    let mut flat = PixelStats::default();
+    for _ in 0..15 {
+        flat.add(0.7);
+    }
+    assert!(!adaptive.converged(&flat));
+    flat.add(0.7);
+    assert!(adaptive.converged(&flat));
+}
@@ -0,0 +1,366 @@
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::film::colors_to_rgb8;
+use crate::film::Film;
+use crate::film::FilmPixel;
+use crate::materials::Material;
+use crate::output::ExrLayer;
+use crate::output::ExrPrecision;
+use crate::output::ExrSamples;
+use crate::rng::mix;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::denoise::Features;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::point3d::Point3D;
+
+// An arbitrary output variable: a render pass written alongside the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aov {
+    // Distance from the camera to what each pixel sees, or infinity where
+    // it only sees the sky.
+    Depth,
+    // World space surface normal.
+    Normal,
+    // Surface color, without lighting.
+    Albedo,
+    // Texture coordinates.
+    Uv,
+    // Which object each pixel sees, counting from 1, or 0 for the sky.
+    ObjectId,
+    // Which material each pixel sees, counting from 1. Objects with the same
+    // material share an ID.
+    MaterialId,
+    // Light reaching the first surface straight from a light or the sky.
+    Direct,
+    // Light reaching the first surface after bouncing off other surfaces.
+    Indirect,
+    // Lights and sky seen directly by the camera.
+    Emission,
+}
+
+// The light carried back along a ray, split by where it came from. The
+// three parts add up to the pixel's color.
+#[derive(Debug, Clone, Copy)]
+pub struct Radiance {
+    pub emission: Srgb,
+    pub direct: Srgb,
+    pub indirect: Srgb,
+}
+
+fn rgb(c: Srgb) -> [f32; 3] {
+    [c.red, c.green, c.blue]
+}
+
+fn black() -> Srgb {
+    Srgb::new(0.0, 0.0, 0.0)
+}
+
+impl Default for Radiance {
+    fn default() -> Self {
+        Radiance {
+            emission: black(),
+            direct: black(),
+            indirect: black(),
+        }
+    }
+}
+
+impl Radiance {
+    pub fn emitted(color: Srgb) -> Radiance {
+        Radiance {
+            emission: color,
+            ..Radiance::default()
+        }
+    }
+
+    pub fn lit(color: Srgb) -> Radiance {
+        Radiance {
+            direct: color,
+            ..Radiance::default()
+        }
+    }
+
+    // Light scattered by a surface: the light found by sampling the lights,
+    // plus this ray's light filtered by albedo. Light the ray found at its
+    // next hit was emitted there, so it is direct light here. Nothing is
+    // clamped, so that HDR output keeps light brighter than white; PNG
+    // output clamps when the image is written.
+    pub fn scattered(light: Srgb, albedo: Srgb, incoming: &Radiance) -> Radiance {
+        let (light, albedo) = (rgb(light), rgb(albedo));
+        let emitted = rgb(incoming.emission);
+        let (lit, bounced) = (rgb(incoming.direct), rgb(incoming.indirect));
+        let mut direct = [0.0; 3];
+        let mut indirect = [0.0; 3];
+        for c in 0..3 {
+            direct[c] = light[c] + albedo[c] * emitted[c];
+            indirect[c] = albedo[c] * (lit[c] + bounced[c]);
+        }
+        Radiance {
+            emission: black(),
+            direct: Srgb::new(direct[0], direct[1], direct[2]),
+            indirect: Srgb::new(indirect[0], indirect[1], indirect[2]),
+        }
+    }
+
+    pub fn total(&self) -> Srgb {
+        Srgb::new(
+            self.emission.red + self.direct.red + self.indirect.red,
+            self.emission.green + self.direct.green + self.indirect.green,
+            self.emission.blue + self.direct.blue + self.indirect.blue,
+        )
+    }
+}
+
+// The AOVs accumulated for one pixel, besides the denoiser's features.
+// Texture coordinates are summed over the pixel's own samples, and the parts
+// of the light are weighted by the pixel filter like the color.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AovSums {
+    // How many of the pixel's own samples hit a surface. The depth, normal
+    // and texture coordinates are averaged over these.
+    pub hits: u32,
+    pub uv: [f64; 2],
+    pub emission: [f64; 3],
+    pub direct: [f64; 3],
+    pub indirect: [f64; 3],
+    // The IDs seen by the pixel's first sample, as they cannot be averaged.
+    pub object: u32,
+    pub material: u32,
+}
+
+impl AovSums {
+    pub fn splat(&mut self, radiance: &Radiance, weight: f64) {
+        let parts = [radiance.emission, radiance.direct, radiance.indirect];
+        let sums = [&mut self.emission, &mut self.direct, &mut self.indirect];
+        for (sum, part) in sums.into_iter().zip(parts) {
+            sum[0] += weight * part.red as f64;
+            sum[1] += weight * part.green as f64;
+            sum[2] += weight * part.blue as f64;
+        }
+    }
+
+    // Adds the sums from another pixel, keeping this pixel's IDs.
+    pub fn add(&mut self, other: &AovSums) {
+        self.hits += other.hits;
+        self.uv[0] += other.uv[0];
+        self.uv[1] += other.uv[1];
+        for c in 0..3 {
+            self.emission[c] += other.emission[c];
+            self.direct[c] += other.direct[c];
+            self.indirect[c] += other.indirect[c];
+        }
+    }
+}
+
+// Materials are numbered by their JSON, so that objects made of the same
+// material share an ID.
+fn material_key(material: &Material) -> String {
+    serde_json::to_string(material).unwrap_or_default()
+}
+
+// The IDs of the front and back materials of each object.
+pub fn material_ids(objects: &[Sphere]) -> Vec<[u32; 2]> {
+    let mut ids: HashMap<String, u32> = HashMap::new();
+    let mut id = |material: &Material| {
+        let next = ids.len() as u32 + 1;
+        *ids.entry(material_key(material)).or_insert(next)
+    };
+    objects
+        .iter()
+        .map(|object| {
+            let front = id(&object.material);
+            let back = object.back_material.as_ref().map_or(front, &mut id);
+            [front, back]
+        })
+        .collect()
+}
+
+// The depth written for the sky by formats that cannot hold infinity. It is
+// far beyond any scene, but small enough for a Radiance HDR file.
+const SKY_DEPTH: f64 = 1e30;
+
+fn linear_to_rgb8(colors: &[[f64; 3]]) -> Vec<u8> {
+    colors
+        .iter()
+        .flat_map(|color| color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+        .collect()
+}
+
+impl Aov {
+    // The name added to the output file name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Uv => "uv",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+        }
+    }
+
+    fn value(&self, pixel: &FilmPixel) -> [f64; 3] {
+        let aovs = &pixel.aovs;
+        let per_hit = 1.0 / aovs.hits.max(1) as f64;
+        let per_weight = if pixel.weight == 0.0 {
+            0.0
+        } else {
+            1.0 / pixel.weight
+        };
+        match self {
+            Aov::Depth if aovs.hits == 0 => [f64::INFINITY; 3],
+            Aov::Depth => [pixel.features.depth * per_hit; 3],
+            Aov::Normal => pixel.features.normal.map(|c| c * per_hit),
+            Aov::Albedo => pixel.features().albedo,
+            Aov::Uv => [aovs.uv[0] * per_hit, aovs.uv[1] * per_hit, 0.0],
+            Aov::ObjectId => [aovs.object as f64; 3],
+            Aov::MaterialId => [aovs.material as f64; 3],
+            Aov::Direct => aovs.direct.map(|c| c * per_weight),
+            Aov::Indirect => aovs.indirect.map(|c| c * per_weight),
+            Aov::Emission => aovs.emission.map(|c| c * per_weight),
+        }
+    }
+
+    // The value of this AOV for every pixel, unscaled.
+    pub fn values(&self, film: &Film) -> Vec<[f64; 3]> {
+        film.pixels.iter().map(|p| self.value(p)).collect()
+    }
+
+    // The values, with normals mapped from [-1, 1] to [0, 1] and the sky's
+    // depth made finite, for formats that cannot hold negative or infinite
+    // values.
+    pub fn positive_values(&self, film: &Film) -> Vec<[f64; 3]> {
+        let values = self.values(film);
+        match self {
+            Aov::Normal => values.iter().map(|v| v.map(|c| 0.5 * c + 0.5)).collect(),
+            Aov::Depth => values.iter().map(|v| v.map(|c| c.min(SKY_DEPTH))).collect(),
+            _ => values,
+        }
+    }
+
+    // The names of the channels this AOV is written to in OpenEXR files.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::Uv => &["U", "V"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Albedo | Aov::Direct | Aov::Indirect | Aov::Emission => &["R", "G", "B"],
+        }
+    }
+
+    // This AOV as a layer of an OpenEXR file, with its raw values. Colors
+    // have the given precision, while positions are always full floats.
+    pub fn exr_layer(&self, film: &Film, precision: ExrPrecision) -> ExrLayer {
+        let samples = match self {
+            Aov::Depth | Aov::Normal | Aov::Uv => ExrSamples::Float,
+            Aov::ObjectId | Aov::MaterialId => ExrSamples::Integer,
+            Aov::Albedo | Aov::Direct | Aov::Indirect | Aov::Emission => precision.into(),
+        };
+        ExrLayer {
+            name: self.name(),
+            channels: self.channels(),
+            values: self.values(film),
+            samples,
+        }
+    }
+
+    // This AOV as an 8-bit RGB image. Colors have gamma 2 like the image,
+    // depth is scaled so that the furthest surface is white, normals are
+    // mapped from [-1, 1] and each ID is given its own color.
+    pub fn to_rgb8(&self, film: &Film) -> Vec<u8> {
+        let values = self.values(film);
+        match self {
+            Aov::Albedo | Aov::Direct | Aov::Indirect | Aov::Emission => colors_to_rgb8(&values),
+            Aov::Depth => {
+                let furthest = values
+                    .iter()
+                    .map(|v| v[0])
+                    .filter(|depth| depth.is_finite())
+                    .fold(0.0, f64::max);
+                let scaled: Vec<[f64; 3]> = values
+                    .iter()
+                    .map(|v| {
+                        [if v[0].is_finite() {
+                            v[0] / furthest
+                        } else {
+                            1.0
+                        }; 3]
+                    })
+                    .collect();
+                linear_to_rgb8(&scaled)
+            }
+            Aov::Normal => linear_to_rgb8(&self.positive_values(film)),
+            Aov::Uv => linear_to_rgb8(&values),
+            Aov::ObjectId | Aov::MaterialId => values
+                .iter()
+                .flat_map(|v| match v[0] as u64 {
+                    0 => [0; 3],
+                    id => {
+                        let bits = mix(id).to_le_bytes();
+                        [bits[0] | 0x40, bits[1] | 0x40, bits[2] | 0x40]
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[test]
+fn test_radiance_split() {
+    let sky = Radiance::emitted(Srgb::new(0.5, 0.5, 0.5));
+    let albedo = Srgb::new(0.5, 0.5, 0.5);
+    let once = Radiance::scattered(Srgb::new(0.1, 0.0, 0.0), albedo, &sky);
+    assert_eq!(once.direct, Srgb::new(0.35, 0.25, 0.25));
+    assert_eq!(once.indirect, black());
+    let twice = Radiance::scattered(black(), albedo, &once);
+    assert_eq!(twice.direct, black());
+    assert_eq!(twice.indirect, Srgb::new(0.175, 0.125, 0.125));
+    // Light brighter than white is kept.
+    let bright = Radiance::emitted(Srgb::new(4.0, 1.0, 1.0));
+    let lit = Radiance::scattered(black(), Srgb::new(0.5, 0.5, 0.5), &bright);
+    assert_eq!(lit.total(), Srgb::new(2.0, 0.5, 0.5));
+}
+
+#[test]
+fn test_material_ids() {
+    let red = || Material::Lambertian(Lambertian::new(Srgb::new(1.0, 0.0, 0.0)));
+    let green = || Material::Lambertian(Lambertian::new(Srgb::new(0.0, 1.0, 0.0)));
+    let mut two_sided = Sphere::new(Point3D::new(0.0, 0.0, 0.0), 1.0, green());
+    two_sided.back_material = Some(red());
+    let objects = vec![
+        Sphere::new(Point3D::new(0.0, 0.0, 0.0), 1.0, red()),
+        Sphere::new(Point3D::new(0.0, 0.0, 0.0), 1.0, green()),
+        two_sided,
+    ];
+    assert_eq!(material_ids(&objects), vec![[1, 1], [2, 2], [2, 1]]);
+    assert_eq!(Aov::ObjectId.name(), "object_id");
+}
+
+#[test]
+fn test_aov_hit_averages() {
+    // Two of four samples hit a surface at depth 2, the others saw the sky.
+    let mut film = Film::new(2, 1);
+    film.pixels[0].stats.count = 4;
+    film.pixels[0].aovs.hits = 2;
+    film.pixels[0].aovs.uv = [1.0, 0.5];
+    film.pixels[0].features = Features {
+        albedo: [1.0; 3],
+        normal: [0.0, 2.0, 0.0],
+        depth: 4.0,
+    };
+    film.pixels[1].stats.count = 4;
+    assert_eq!(Aov::Depth.values(&film), [[2.0; 3], [f64::INFINITY; 3]]);
+    assert_eq!(Aov::Normal.values(&film)[0], [0.0, 1.0, 0.0]);
+    assert_eq!(Aov::Uv.values(&film)[0], [0.5, 0.25, 0.0]);
+    assert_eq!(Aov::Depth.positive_values(&film)[1], [SKY_DEPTH; 3]);
+}
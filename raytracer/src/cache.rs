@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+// Values loaded from files, such as texture images and measured BRDFs, so
+// that everything using the same file shares one copy. Only weak references
+// are kept, so a value is freed once nothing uses it.
+pub type SharedCache<K, T> = OnceLock<Mutex<HashMap<K, Weak<T>>>>;
+
+// The key for a file, the same however its path is written.
+pub fn file_key(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+// Returns the value cached for key if it is still in use, or else calls
+// load and caches its result. The lock is not held while loading, so other
+// files can be looked up meanwhile; if two threads load the same file at
+// once, the first to finish is kept.
+pub fn load_shared<K, T>(cache: &SharedCache<K, T>, key: K, load: impl FnOnce() -> T) -> Arc<T>
+where
+    K: Eq + Hash,
+{
+    let cache = cache.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(value) = cache.lock().unwrap().get(&key).and_then(Weak::upgrade) {
+        return value;
+    }
+    let value = Arc::new(load());
+    let mut cache = cache.lock().unwrap();
+    if let Some(existing) = cache.get(&key).and_then(Weak::upgrade) {
+        return existing;
+    }
+    cache.retain(|_, value| value.strong_count() > 0);
+    cache.insert(key, Arc::downgrade(&value));
+    value
+}
+
+#[test]
+fn test_load_shared() {
+    static CACHE: SharedCache<u32, String> = OnceLock::new();
+    let a = load_shared(&CACHE, 1, || "a".to_string());
+    let b = load_shared(&CACHE, 1, || panic!("loaded twice"));
+    assert!(Arc::ptr_eq(&a, &b));
+    // Once nothing uses a value it is loaded again.
+    drop((a, b));
+    let c = load_shared(&CACHE, 1, || "c".to_string());
+    assert_eq!(*c, "c");
+}
@@ -76,6 +76,11 @@ impl Camera {
         }
     }
 
+    // The angle subtended by one pixel of an image with the given height.
+    pub fn pixel_spread(&self, image_height: usize) -> f64 {
+        self.vertical.length() / image_height as f64
+    }
+
     pub fn get_ray(&self, u: f64, v: f64) -> Ray {
         Ray::new(
             self.origin,
@@ -91,7 +96,7 @@ fn test_camera() {
         Point3D::new(0.0, 0.0, -1.0),
         Point3D::new(0.0, 1.0, 0.0),
         90.0,
-        (800.0 / 600.0) as f64,
+        800.0 / 600.0,
     );
     assert_eq!(camera.origin.x(), 0.0);
     assert_eq!(camera.origin.y(), 0.0);
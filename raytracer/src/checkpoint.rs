@@ -0,0 +1,193 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::adaptive::PixelStats;
+use crate::aov::AovSums;
+use crate::denoise::Features;
+use crate::film::Film;
+use crate::film::FilmPixel;
+
+#[cfg(test)]
+use palette::Srgb;
+
+#[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
+use crate::materials::Texture;
+#[cfg(test)]
+use crate::temp_path;
+
+const MAGIC: &[u8; 4] = b"RTCK";
+const VERSION: u32 = 5;
+
+// A 64-bit FNV-1a hash of a scene, so that a checkpoint is not resumed
+// with a different scene.
+pub fn fingerprint(scene_json: &str) -> u64 {
+    scene_json.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Saves the film and the number of passes rendered so far. The file is
+// written next to the checkpoint and then renamed over it, so a render
+// killed while saving leaves the previous checkpoint intact. There is no
+// random number state to save, as each sample's random numbers come from
+// the seed, its pixel and its number, which the sample counts hold.
+pub fn save(path: &str, film: &Film, pass: usize, fingerprint: u64) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(32 + film.pixels.len() * 208);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&fingerprint.to_le_bytes());
+    bytes.extend_from_slice(&(film.width as u32).to_le_bytes());
+    bytes.extend_from_slice(&(film.height as u32).to_le_bytes());
+    bytes.extend_from_slice(&(pass as u64).to_le_bytes());
+    for pixel in &film.pixels {
+        for c in pixel.sum {
+            bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        bytes.extend_from_slice(&pixel.weight.to_le_bytes());
+        bytes.extend_from_slice(&pixel.stats.count.to_le_bytes());
+        bytes.extend_from_slice(&pixel.stats.mean.to_le_bytes());
+        bytes.extend_from_slice(&pixel.stats.m2.to_le_bytes());
+        let features = &pixel.features;
+        for value in features.albedo.iter().chain(&features.normal) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&features.depth.to_le_bytes());
+        let aovs = &pixel.aovs;
+        bytes.extend_from_slice(&aovs.hits.to_le_bytes());
+        let sums = aovs.uv.iter().chain(&aovs.emission);
+        for value in sums.chain(&aovs.direct).chain(&aovs.indirect) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&aovs.object.to_le_bytes());
+        bytes.extend_from_slice(&aovs.material.to_le_bytes());
+    }
+    let temporary = format!("{}.tmp", path);
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        if self.bytes.len() < N {
+            return Err(invalid("truncated checkpoint"));
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+}
+
+// Loads a film and pass count saved for a scene with the given fingerprint
+// and image size.
+pub fn load(
+    path: &str,
+    fingerprint: u64,
+    width: usize,
+    height: usize,
+) -> io::Result<(Film, usize)> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    let mut reader = Reader { bytes: &bytes };
+    if &reader.take::<4>()? != MAGIC || reader.u32()? != VERSION {
+        return Err(invalid("not a checkpoint file"));
+    }
+    if reader.u64()? != fingerprint {
+        return Err(invalid("checkpoint is for a different scene"));
+    }
+    if reader.u32()? as usize != width || reader.u32()? as usize != height {
+        return Err(invalid("checkpoint is for a different image size"));
+    }
+    let pass = reader.u64()? as usize;
+    let mut film = Film::new(width, height);
+    for pixel in film.pixels.iter_mut() {
+        *pixel = FilmPixel {
+            sum: [reader.f64()?, reader.f64()?, reader.f64()?],
+            weight: reader.f64()?,
+            stats: PixelStats {
+                count: reader.u32()?,
+                mean: reader.f64()?,
+                m2: reader.f64()?,
+            },
+            features: Features {
+                albedo: [reader.f64()?, reader.f64()?, reader.f64()?],
+                normal: [reader.f64()?, reader.f64()?, reader.f64()?],
+                depth: reader.f64()?,
+            },
+            aovs: AovSums {
+                hits: reader.u32()?,
+                uv: [reader.f64()?, reader.f64()?],
+                emission: [reader.f64()?, reader.f64()?, reader.f64()?],
+                direct: [reader.f64()?, reader.f64()?, reader.f64()?],
+                indirect: [reader.f64()?, reader.f64()?, reader.f64()?],
+                object: reader.u32()?,
+                material: reader.u32()?,
+            },
+        };
+    }
+    Ok((film, pass))
+}
+
+#[test]
+fn test_checkpoint_round_trip() {
+    let mut film = Film::new(3, 2);
+    film.pixels[4].add(Srgb::new(0.25, 0.5, 1.0));
+    film.pixels[4].add(Srgb::new(0.75, 0.5, 0.0));
+    film.pixels[4].features.depth = 3.0;
+    film.pixels[4].aovs.hits = 1;
+    film.pixels[4].aovs.indirect[1] = 0.5;
+    film.pixels[4].aovs.material = 2;
+    let id = fingerprint("{\"width\":3}");
+    let path = temp_path("checkpoint_test.bin");
+    save(&path, &film, 7, id).unwrap();
+
+    let (loaded, pass) = load(&path, id, 3, 2).unwrap();
+    assert_eq!(pass, 7);
+    assert_eq!(loaded.pixels[4].sum, film.pixels[4].sum);
+    assert_eq!(loaded.pixels[4].weight, 2.0);
+    assert_eq!(loaded.pixels[4].features().depth, 1.5);
+    assert_eq!(loaded.pixels[4].aovs.indirect, [0.0, 0.5, 0.0]);
+    assert_eq!(loaded.pixels[4].aovs.material, 2);
+    assert_eq!(loaded.pixels[4].aovs.hits, 1);
+    assert_eq!(loaded.pixels[4].stats.count, 2);
+    assert_eq!(loaded.pixels[4].stats.m2, film.pixels[4].stats.m2);
+    assert_eq!(loaded.to_rgb8(), film.to_rgb8());
+
+    assert!(load(&path, id + 1, 3, 2).is_err());
+    assert!(load(&path, id, 2, 3).is_err());
+    assert_ne!(fingerprint("a"), fingerprint("b"));
+}
+
+#[test]
+fn test_fingerprint_textures() {
+    // Swapping a texture image changes the fingerprint.
+    let scene = |image: &str| {
+        let texture = Texture::new(Srgb::new(1.0, 1.0, 1.0), image, 0.0);
+        fingerprint(&serde_json::to_string(&Material::Texture(texture)).unwrap())
+    };
+    assert_ne!(scene("data/earth.jpg"), scene("data/moon.jpg"));
+}
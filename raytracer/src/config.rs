@@ -1,18 +1,26 @@
-use jpeg_decoder::Decoder;
 use palette::Srgb;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
-use std::fs::File;
-use std::io::BufReader;
+use std::sync::Arc;
 
+use crate::adaptive::AdaptiveSampling;
+use crate::aov::Aov;
 use crate::camera::Camera;
+use crate::denoise::Denoise;
+use crate::filter::PixelFilter;
 use crate::materials::Glass;
 use crate::materials::Lambertian;
 use crate::materials::Material;
 use crate::materials::Metal;
+use crate::outline::Outline;
+use crate::output::ExrPrecision;
 use crate::point3d::Point3D;
+use crate::sampler::SamplerKind;
 use crate::sphere::Sphere;
+use crate::textures::is_default;
+use crate::textures::Filter;
+use crate::textures::MipMap;
+use crate::textures::WrapMode;
 
 #[cfg(test)]
 use std::fs;
@@ -24,32 +32,34 @@ pub struct Sky {
     // projected texture loaded from an image file at this path. Else,
     // a light blue colored sky will be used.
     #[serde_as(as = "TextureOptionPixelsAsPath")]
-    pub texture: Option<(Vec<u8>, usize, usize, String)>,
+    pub texture: Option<(Arc<MipMap>, usize, usize, String)>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub filter: Filter,
+    // How the texture wraps horizontally. It is always clamped vertically.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub wrap: WrapMode,
 }
 
 impl Sky {
     pub fn new_default_sky() -> Sky {
-        Sky { texture: None }
+        Sky {
+            texture: None,
+            filter: Filter::default(),
+            wrap: WrapMode::default(),
+        }
     }
 }
 
-fn load_texture_image(path: &str) -> (Vec<u8>, usize, usize, String) {
-    let file = File::open(path).expect(path);
-    let mut decoder = Decoder::new(BufReader::new(file));
-    let pixels = decoder.decode().expect("failed to decode image");
-    let metadata = decoder.info().unwrap();
-    (
-        pixels,
-        metadata.width as usize,
-        metadata.height as usize,
-        path.to_string(),
-    )
+fn load_texture_image(path: &str) -> (Arc<MipMap>, usize, usize, String) {
+    let pixels = MipMap::load(path, false);
+    let (width, height) = (pixels.width(), pixels.height());
+    (pixels, width, height, path.to_string())
 }
 
 serde_with::serde_conv!(
     TextureOptionPixelsAsPath,
-    Option<(Vec<u8>, usize, usize, String)>,
-    |texture: &Option<(Vec<u8>, usize, usize, String)>| {
+    Option<(Arc<MipMap>, usize, usize, String)>,
+    |texture: &Option<(Arc<MipMap>, usize, usize, String)>| {
         match texture {
             Some(tuple) => tuple.3.clone(),
             None => "".to_string(),
@@ -63,6 +73,22 @@ serde_with::serde_conv!(
     }
 );
 
+fn sixteen() -> u32 {
+    16
+}
+
+// Renders in passes of samples_per_pass samples per pixel, writing the
+// image so far after each pass.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Progressive {
+    #[serde(default = "sixteen")]
+    pub samples_per_pass: u32,
+    // Write each pass to a numbered file, such as out_0001.png, instead of
+    // overwriting the output.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub snapshots: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub width: usize,
@@ -72,6 +98,32 @@ pub struct Config {
     pub sky: Option<Sky>,
     pub camera: Camera,
     pub objects: Vec<Sphere>,
+    // If provided, silhouette and crease lines are drawn over the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outline: Option<Outline>,
+    // If provided, pixels stop taking samples once they are smooth enough.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<AdaptiveSampling>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progressive: Option<Progressive>,
+    // Renders with the same seed are identical.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub seed: u64,
+    // How the random numbers for each sample are spread out.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub sampler: SamplerKind,
+    // How samples are weighted into the pixels around them.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub filter: PixelFilter,
+    // If provided, noise is smoothed away from the finished image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub denoise: Option<Denoise>,
+    // Render passes written next to the image, such as out_depth.png.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aovs: Vec<Aov>,
+    // Whether .exr output is written with half or full floats.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exr_precision: ExrPrecision,
 }
 
 #[test]
@@ -92,10 +144,17 @@ fn test_to_json() {
         objects: vec![Sphere::new(
             Point3D::new(0.0, 0.0, -1.0),
             0.5,
-            Material::Lambertian(Lambertian::new(Srgb::new(
-                0.8 as f32, 0.3 as f32, 0.3 as f32,
-            ))),
+            Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.3, 0.3))),
         )],
+        outline: None,
+        seed: 0,
+        sampler: SamplerKind::Independent,
+        filter: PixelFilter::default(),
+        denoise: None,
+        aovs: vec![],
+        exr_precision: ExrPrecision::Half,
+        adaptive: None,
+        progressive: None,
     };
     let serialized = serde_json::to_string(&config).unwrap();
     assert_eq!("{\"width\":100,\"height\":100,\"samples_per_pixel\":1,\"max_depth\":1,\"sky\":{\"texture\":\"\"},\"camera\":{\"look_from\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"look_at\":{\"x\":0.0,\"y\":0.0,\"z\":-1.0},\"vup\":{\"x\":0.0,\"y\":1.0,\"z\":0.0},\"vfov\":90.0,\"aspect\":1.0},\"objects\":[{\"center\":{\"x\":0.0,\"y\":0.0,\"z\":-1.0},\"radius\":0.5,\"material\":{\"Lambertian\":{\"albedo\":[0.8,0.3,0.3]}}}]}", serialized);
@@ -119,10 +178,17 @@ fn test_sky_perms_to_from_json() {
         objects: vec![Sphere::new(
             Point3D::new(0.0, 0.0, -1.0),
             0.5,
-            Material::Lambertian(Lambertian::new(Srgb::new(
-                0.8 as f32, 0.3 as f32, 0.3 as f32,
-            ))),
+            Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.3, 0.3))),
         )],
+        outline: None,
+        seed: 0,
+        sampler: SamplerKind::Independent,
+        filter: PixelFilter::default(),
+        denoise: None,
+        aovs: vec![],
+        exr_precision: ExrPrecision::Half,
+        adaptive: None,
+        progressive: None,
     };
     let serialized = serde_json::to_string(&config).unwrap();
     assert_eq!("{\"width\":100,\"height\":100,\"samples_per_pixel\":1,\"max_depth\":1,\"sky\":null,\"camera\":{\"look_from\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"look_at\":{\"x\":0.0,\"y\":0.0,\"z\":-1.0},\"vup\":{\"x\":0.0,\"y\":1.0,\"z\":0.0},\"vfov\":90.0,\"aspect\":1.0},\"objects\":[{\"center\":{\"x\":0.0,\"y\":0.0,\"z\":-1.0},\"radius\":0.5,\"material\":{\"Lambertian\":{\"albedo\":[0.8,0.3,0.3]}}}]}", serialized);
@@ -130,7 +196,7 @@ fn test_sky_perms_to_from_json() {
 
     // This scene contains a sky texture at data/earth,jpg
     let scene_json = "{\"width\":100,\"height\":100,\"samples_per_pixel\":1,\"max_depth\":1,\"sky\":{\"texture\":\"data/earth.jpg\"},\"camera\":{\"look_from\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"look_at\":{\"x\":0.0,\"y\":0.0,\"z\":-1.0},\"vup\":{\"x\":0.0,\"y\":1.0,\"z\":0.0},\"vfov\":90.0,\"aspect\":1.0},\"objects\":[{\"center\":{\"x\":0.0,\"y\":0.0,\"z\":-1.0},\"radius\":0.5,\"material\":{\"Lambertian\":{\"albedo\":[0.8,0.3,0.3]}}}]}";
-    let scene = serde_json::from_str::<Config>(&scene_json).expect("Unable to parse json");
+    let scene = serde_json::from_str::<Config>(scene_json).expect("Unable to parse json");
 
     assert_eq!(
         match scene.sky {
@@ -210,17 +276,12 @@ fn _make_cover_world() -> Vec<Sphere> {
     world.push(Sphere::new(
         Point3D::new(-4.0, 1.0, 0.0),
         1.0,
-        Material::Lambertian(Lambertian::new(Srgb::new(
-            0.4 as f32, 0.2 as f32, 0.1 as f32,
-        ))),
+        Material::Lambertian(Lambertian::new(Srgb::new(0.4, 0.2, 0.1))),
     ));
     world.push(Sphere::new(
         Point3D::new(4.0, 1.0, 0.0),
         1.0,
-        Material::Metal(Metal::new(
-            Srgb::new(0.7 as f32, 0.6 as f32, 0.5 as f32),
-            0.0,
-        )),
+        Material::Metal(Metal::new(Srgb::new(0.7, 0.6, 0.5), 0.0)),
     ));
     world
 }
@@ -238,9 +299,18 @@ fn test_cover_scene_to_json() {
             Point3D::new(0.0, 0.0, 0.0),
             Point3D::new(0.0, 1.0, 0.0),
             20.0,
-            (800.0 / 600.0) as f64,
+            800.0 / 600.0,
         ),
         objects: _make_cover_world(),
+        outline: None,
+        seed: 0,
+        sampler: SamplerKind::Independent,
+        filter: PixelFilter::default(),
+        denoise: None,
+        aovs: vec![],
+        exr_precision: ExrPrecision::Half,
+        adaptive: None,
+        progressive: None,
     };
     let serialized = serde_json::to_string_pretty(&config).unwrap();
     fs::write("/tmp/cover_scene.json", serialized).unwrap();
@@ -253,3 +323,25 @@ fn test_from_file() {
     assert_eq!(scene.width, 800);
     assert_eq!(scene.height, 600);
 }
+
+#[test]
+fn test_textures_shared_between_objects() {
+    let texture = "{\"Texture\":{\"albedo\":[1.0,1.0,1.0],\"pixels\":\"data/earth.jpg\",\"width\":2048,\"height\":1024,\"h_offset\":0.0}}";
+    let sphere = format!(
+        "{{\"center\":{{\"x\":0.0,\"y\":0.0,\"z\":-1.0}},\"radius\":0.5,\"material\":{}}}",
+        texture
+    );
+    let scene_json = format!("{{\"width\":100,\"height\":100,\"samples_per_pixel\":1,\"max_depth\":1,\"sky\":{{\"texture\":\"data/earth.jpg\"}},\"camera\":{{\"look_from\":{{\"x\":0.0,\"y\":0.0,\"z\":0.0}},\"look_at\":{{\"x\":0.0,\"y\":0.0,\"z\":-1.0}},\"vup\":{{\"x\":0.0,\"y\":1.0,\"z\":0.0}},\"vfov\":90.0,\"aspect\":1.0}},\"objects\":[{},{}]}}", sphere, sphere);
+    let scene = serde_json::from_str::<Config>(&scene_json).expect("Unable to parse json");
+    let pixels: Vec<&Arc<MipMap>> = scene
+        .objects
+        .iter()
+        .map(|o| match &o.material {
+            Material::Texture(t) => &t.pixels,
+            _ => panic!("Wrong material type"),
+        })
+        .collect();
+    let sky = &scene.sky.as_ref().unwrap().texture.as_ref().unwrap().0;
+    assert!(Arc::ptr_eq(pixels[0], pixels[1]));
+    assert!(Arc::ptr_eq(pixels[0], sky));
+}
@@ -0,0 +1,288 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::film::Film;
+
+#[cfg(test)]
+use palette::Srgb;
+
+fn five() -> u32 {
+    5
+}
+
+fn color_sigma() -> f64 {
+    4.0
+}
+
+fn normal_power() -> f64 {
+    128.0
+}
+
+fn depth_sigma() -> f64 {
+    0.1
+}
+
+fn albedo_sigma() -> f64 {
+    0.1
+}
+
+// The filter taps of a B3 spline, which the a-trous filter spreads further
+// apart on each iteration.
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Albedo below this is treated as this, so that dark surfaces can still be
+// demodulated.
+const MIN_ALBEDO: f64 = 0.01;
+
+// Smooths away noise after rendering with an edge-avoiding a-trous wavelet
+// filter (Dammertz et al. 2010). The filter is guided by the albedo, normal
+// and depth seen by each pixel's samples, so that it does not blur across
+// object edges, creases or texture detail, and by each pixel's variance, so
+// that it smooths noisy pixels more than clean ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Denoise {
+    // Each iteration doubles the distance the filter reaches.
+    #[serde(default = "five")]
+    pub iterations: u32,
+    // How many standard deviations of noise a difference in brightness must
+    // be to count as an edge.
+    #[serde(default = "color_sigma")]
+    pub color_sigma: f64,
+    // How sharply differences in normals stop the filter.
+    #[serde(default = "normal_power")]
+    pub normal_power: f64,
+    // Relative difference in depth, per pixel apart, treated as an edge.
+    #[serde(default = "depth_sigma")]
+    pub depth_sigma: f64,
+    // Difference in albedo treated as an edge.
+    #[serde(default = "albedo_sigma")]
+    pub albedo_sigma: f64,
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The average albedo, normal and depth where a pixel's samples first hit the
+// scene. Samples that see the sky add nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub albedo: [f64; 3],
+    pub normal: [f64; 3],
+    pub depth: f64,
+}
+
+impl Features {
+    pub fn add(&mut self, other: &Features) {
+        for c in 0..3 {
+            self.albedo[c] += other.albedo[c];
+            self.normal[c] += other.normal[c];
+        }
+        self.depth += other.depth;
+    }
+
+    pub fn scale(&self, factor: f64) -> Features {
+        Features {
+            albedo: self.albedo.map(|c| c * factor),
+            normal: self.normal.map(|c| c * factor),
+            depth: self.depth * factor,
+        }
+    }
+}
+
+fn luminance(c: [f64; 3]) -> f64 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+impl Denoise {
+    pub fn new() -> Denoise {
+        Denoise {
+            iterations: five(),
+            color_sigma: color_sigma(),
+            normal_power: normal_power(),
+            depth_sigma: depth_sigma(),
+            albedo_sigma: albedo_sigma(),
+        }
+    }
+
+    // The denoised colors of the film's pixels, in linear color.
+    pub fn apply(&self, film: &Film) -> Vec<[f64; 3]> {
+        let (width, height) = (film.width, film.height);
+        let features: Vec<Features> = film.pixels.iter().map(|p| p.features()).collect();
+        let albedo: Vec<[f64; 3]> = features
+            .iter()
+            .map(|f| f.albedo.map(|c| c.max(MIN_ALBEDO)))
+            .collect();
+        // Filter the lighting with the albedo divided out, so that texture
+        // detail is not blurred, and the variance of its brightness.
+        let mut irradiance: Vec<[f64; 3]> = film
+            .pixels
+            .iter()
+            .zip(&albedo)
+            .map(|(p, a)| {
+                let color = p.color();
+                [color[0] / a[0], color[1] / a[1], color[2] / a[2]]
+            })
+            .collect();
+        let mut variance: Vec<f64> = film
+            .pixels
+            .iter()
+            .zip(&albedo)
+            .map(|(p, a)| {
+                if p.stats.count < 2 {
+                    // Unknown, so let brightness differences through.
+                    1.0
+                } else {
+                    p.stats.variance() / p.stats.count as f64 / luminance(*a).powi(2)
+                }
+            })
+            .collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1isize << iteration;
+            let local_variance = blur(&variance, width, height);
+            let filtered: Vec<([f64; 3], f64)> = (0..width * height)
+                .into_par_iter()
+                .map(|p| {
+                    let (x, y) = ((p % width) as isize, (p / width) as isize);
+                    let center = luminance(irradiance[p]);
+                    let color_scale = self.color_sigma * local_variance[p].sqrt() + 1e-6;
+                    let mut sum = [0.0; 3];
+                    let mut sum_variance = 0.0;
+                    let mut total = 0.0;
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        let qy = y + (j as isize - 2) * step;
+                        if qy < 0 || qy >= height as isize {
+                            continue;
+                        }
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let qx = x + (i as isize - 2) * step;
+                            if qx < 0 || qx >= width as isize {
+                                continue;
+                            }
+                            let q = qy as usize * width + qx as usize;
+                            let weight = if q == p {
+                                kx * ky
+                            } else {
+                                let color_difference = (luminance(irradiance[q]) - center).abs();
+                                kx * ky
+                                    * (-color_difference / color_scale).exp()
+                                    * self.feature_weight(&features[p], &features[q], step)
+                            };
+                            for c in 0..3 {
+                                sum[c] += weight * irradiance[q][c];
+                            }
+                            sum_variance += weight * weight * variance[q];
+                            total += weight;
+                        }
+                    }
+                    (sum.map(|c| c / total), sum_variance / (total * total))
+                })
+                .collect();
+            (irradiance, variance) = filtered.into_iter().unzip();
+        }
+
+        irradiance
+            .iter()
+            .zip(&albedo)
+            .map(|(e, a)| [e[0] * a[0], e[1] * a[1], e[2] * a[2]])
+            .collect()
+    }
+
+    // How much pixel q should contribute to pixel p, step pixels apart, given
+    // what each of them sees.
+    fn feature_weight(&self, p: &Features, q: &Features, step: isize) -> f64 {
+        let normal = (0..3)
+            .map(|c| p.normal[c] * q.normal[c])
+            .sum::<f64>()
+            .max(0.0)
+            .powf(self.normal_power);
+        let depth_scale = self.depth_sigma * step as f64 * p.depth.max(1e-6);
+        let depth = (-(p.depth - q.depth).abs() / depth_scale).exp();
+        let albedo_difference = (0..3)
+            .map(|c| (p.albedo[c] - q.albedo[c]).powi(2))
+            .sum::<f64>();
+        let albedo = (-albedo_difference / (self.albedo_sigma * self.albedo_sigma)).exp();
+        normal * depth * albedo
+    }
+}
+
+// A 3x3 Gaussian blur of the variance, so that a pixel whose few samples
+// happened to agree still expects its neighbours' noise.
+fn blur(variance: &[f64], width: usize, height: usize) -> Vec<f64> {
+    const TAPS: [f64; 3] = [0.25, 0.5, 0.25];
+    (0..width * height)
+        .into_par_iter()
+        .map(|p| {
+            let (x, y) = (p % width, p / width);
+            let mut sum = 0.0;
+            let mut total = 0.0;
+            for (j, ky) in TAPS.iter().enumerate() {
+                for (i, kx) in TAPS.iter().enumerate() {
+                    let (qx, qy) = ((x + i).wrapping_sub(1), (y + j).wrapping_sub(1));
+                    if qx < width && qy < height {
+                        sum += kx * ky * variance[qy * width + qx];
+                        total += kx * ky;
+                    }
+                }
+            }
+            sum / total
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn noisy_film(features: impl Fn(usize, usize) -> (Features, f32)) -> Film {
+    let mut film = Film::new(16, 16);
+    let mut rng = crate::rng::sample_rng(0, 0, 0);
+    for (i, pixel) in film.pixels.iter_mut().enumerate() {
+        let (f, level) = features(i % 16, i / 16);
+        for _ in 0..4 {
+            let noise = rand::Rng::gen_range(&mut rng, -0.2..0.2);
+            pixel.add(Srgb::new(level + noise, level + noise, level + noise));
+            pixel.features.add(&f);
+        }
+    }
+    film
+}
+
+#[cfg(test)]
+fn flat(albedo: f64, normal: [f64; 3]) -> Features {
+    Features {
+        albedo: [albedo; 3],
+        normal,
+        depth: 2.0,
+    }
+}
+
+#[test]
+fn test_denoise_smooths_noise() {
+    let film = noisy_film(|_, _| (flat(0.5, [0.0, 0.0, 1.0]), 0.5));
+    let error = |colors: &[[f64; 3]]| {
+        colors.iter().map(|c| (c[0] - 0.5).powi(2)).sum::<f64>() / colors.len() as f64
+    };
+    let before: Vec<[f64; 3]> = film.pixels.iter().map(|p| p.color()).collect();
+    let after = Denoise::new().apply(&film);
+    assert!(error(&after) < error(&before) / 10.0);
+}
+
+#[test]
+fn test_denoise_keeps_edges() {
+    // Two walls meeting at a crease, and a change of albedo on one of them.
+    let film = noisy_film(|x, y| match (x < 8, y < 8) {
+        (true, _) => (flat(0.5, [1.0, 0.0, 0.0]), 0.2),
+        (false, true) => (flat(0.5, [0.0, 1.0, 0.0]), 0.8),
+        (false, false) => (flat(0.25, [0.0, 1.0, 0.0]), 0.4),
+    });
+    let after = Denoise::new().apply(&film);
+    for (i, color) in after.iter().enumerate() {
+        let expected = match (i % 16 < 8, i / 16 < 8) {
+            (true, _) => 0.2,
+            (false, true) => 0.8,
+            (false, false) => 0.4,
+        };
+        assert!((color[0] - expected).abs() < 0.15);
+    }
+}
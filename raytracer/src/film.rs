@@ -0,0 +1,232 @@
+use palette::Pixel;
+use palette::Srgb;
+use rayon::prelude::*;
+
+use crate::adaptive::PixelStats;
+use crate::aov::AovSums;
+use crate::aov::Radiance;
+use crate::denoise::Features;
+use crate::filter::PixelFilter;
+use crate::textures::luminance;
+
+// Everything accumulated for one pixel so far. The weighted sum of colors
+// includes samples from neighbouring pixels that the pixel filter reaches,
+// but the statistics only cover the samples this pixel took itself. The
+// features and AOVs are only collected when they are needed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FilmPixel {
+    pub sum: [f64; 3],
+    pub weight: f64,
+    pub stats: PixelStats,
+    pub features: Features,
+    pub aovs: AovSums,
+}
+
+impl FilmPixel {
+    // Adds a sample taken in this pixel, with a box filter.
+    pub fn add(&mut self, color: Srgb) {
+        self.splat(color, 1.0);
+        self.stats.add(luminance(color));
+    }
+
+    pub fn splat(&mut self, color: Srgb, weight: f64) {
+        self.sum[0] += weight * color.red as f64;
+        self.sum[1] += weight * color.green as f64;
+        self.sum[2] += weight * color.blue as f64;
+        self.weight += weight;
+    }
+
+    // The weighted average of the samples so far, in linear color.
+    pub fn color(&self) -> [f64; 3] {
+        if self.weight == 0.0 {
+            return [0.0; 3];
+        }
+        self.sum.map(|c| c / self.weight)
+    }
+
+    // The average features of this pixel's own samples.
+    pub fn features(&self) -> Features {
+        if self.stats.count == 0 {
+            return Features::default();
+        }
+        self.features.scale(1.0 / self.stats.count as f64)
+    }
+}
+
+// The filtered samples taken by one row of pixels in a pass, covering the
+// rows above and below that the filter reaches. Rows render in parallel into
+// their own splats, which are then added to the film in order so that the
+// result does not depend on the number of threads.
+#[derive(Debug, Clone)]
+pub struct LineSplats {
+    y: usize,
+    reach: usize,
+    width: usize,
+    height: usize,
+    // Whether the parts of the light are kept for the AOVs.
+    aovs: bool,
+    pixels: Vec<FilmPixel>,
+}
+
+impl LineSplats {
+    pub fn new(y: usize, reach: usize, width: usize, height: usize, aovs: bool) -> LineSplats {
+        LineSplats {
+            y,
+            reach,
+            width,
+            height,
+            aovs,
+            pixels: vec![FilmPixel::default(); (2 * reach + 1) * width],
+        }
+    }
+
+    // Adds a sample at (x, y) in image coordinates, where pixel (i, j)
+    // covers [i, i + 1) x [j, j + 1), to the pixels whose centers are near
+    // enough.
+    pub fn add(&mut self, filter: &PixelFilter, x: f64, y: f64, radiance: &Radiance) {
+        let color = radiance.total();
+        let radius = filter.radius();
+        // Centers c with x - radius < c <= x + radius.
+        let first = |p: f64| (p - 0.5 - radius).floor() as isize + 1;
+        let last = |p: f64| (p - 0.5 + radius).floor() as isize;
+        let top = self.y as isize - self.reach as isize;
+        // The filter is separable, so the weights along each axis are found
+        // once.
+        let columns: Vec<(usize, f64)> = (first(x).max(0)..=last(x).min(self.width as isize - 1))
+            .map(|i| (i as usize, filter.evaluate(i as f64 + 0.5 - x)))
+            .collect();
+        for j in first(y).max(top).max(0)..=last(y).min(top + 2 * self.reach as isize) {
+            if j >= self.height as isize {
+                break;
+            }
+            let row_weight = filter.evaluate(j as f64 + 0.5 - y);
+            let row = (j - top) as usize * self.width;
+            for &(i, column_weight) in &columns {
+                let pixel = &mut self.pixels[row + i];
+                pixel.splat(color, row_weight * column_weight);
+                if self.aovs {
+                    pixel.aovs.splat(radiance, row_weight * column_weight);
+                }
+            }
+        }
+    }
+}
+
+// A floating point image that samples are accumulated into, possibly over
+// several passes.
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<FilmPixel>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![FilmPixel::default(); width * height],
+        }
+    }
+
+    // Adds the splats of every row of a pass, in order, to the film.
+    pub fn add_splats(&mut self, splats: &[LineSplats]) {
+        self.pixels
+            .par_chunks_mut(self.width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for line in splats {
+                    if y + line.reach < line.y || y > line.y + line.reach {
+                        continue;
+                    }
+                    let from = (y + line.reach - line.y) * line.width;
+                    for (pixel, splat) in row.iter_mut().zip(&line.pixels[from..]) {
+                        for c in 0..3 {
+                            pixel.sum[c] += splat.sum[c];
+                        }
+                        pixel.weight += splat.weight;
+                        if line.aovs {
+                            pixel.aovs.add(&splat.aovs);
+                        }
+                    }
+                }
+            });
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.stats.count as u64).sum()
+    }
+
+    // The image so far as 8-bit RGB, with gamma 2.
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let colors: Vec<[f64; 3]> = self.pixels.iter().map(|p| p.color()).collect();
+        colors_to_rgb8(&colors)
+    }
+
+    // The number of samples taken by each pixel, as a grey level.
+    pub fn sample_counts(&self) -> Vec<[f64; 3]> {
+        self.pixels
+            .iter()
+            .map(|p| [p.stats.count as f64; 3])
+            .collect()
+    }
+
+    // The number of samples taken by each pixel as 8-bit grey levels, where
+    // white is max_samples.
+    pub fn sample_counts_rgb8(&self, max_samples: u32) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|p| {
+                let level = (255 * p.stats.count as u64 / max_samples.max(1) as u64).min(255);
+                [level as u8; 3]
+            })
+            .collect()
+    }
+}
+
+// Linear colors as 8-bit RGB, with gamma 2.
+pub fn colors_to_rgb8(colors: &[[f64; 3]]) -> Vec<u8> {
+    colors
+        .iter()
+        .flat_map(|color| {
+            let [r, g, b] = color.map(|c| c.clamp(0.0, 1.0).sqrt() as f32);
+            let pixel: [u8; 3] = Srgb::new(r, g, b).into_format().into_raw();
+            pixel
+        })
+        .collect()
+}
+
+#[test]
+fn test_film() {
+    let mut film = Film::new(2, 1);
+    film.pixels[0].add(Srgb::new(1.0, 0.25, 0.0));
+    film.pixels[0].add(Srgb::new(0.0, 0.25, 0.0));
+    assert_eq!(film.pixels[0].color(), [0.5, 0.25, 0.0]);
+    assert_eq!(film.total_samples(), 2);
+    assert_eq!(film.to_rgb8(), vec![180, 128, 0, 0, 0, 0]);
+    assert_eq!(film.sample_counts_rgb8(4), vec![127, 127, 127, 0, 0, 0]);
+}
+
+#[test]
+fn test_splats() {
+    let tent = PixelFilter::Tent { radius: 1.0 };
+    let mut film = Film::new(3, 3);
+    let mut splats: Vec<LineSplats> = (0..3).map(|y| LineSplats::new(y, 1, 3, 3, true)).collect();
+    // At the center of pixel (1, 1), only that pixel is reached.
+    let white = Radiance::emitted(Srgb::new(1.0, 1.0, 1.0));
+    splats[1].add(&tent, 1.5, 1.5, &white);
+    // At the corner between four pixels, each gets a quarter.
+    let blue = Radiance::lit(Srgb::new(0.0, 0.0, 1.0));
+    splats[0].add(&tent, 1.0, 1.0, &blue);
+    film.add_splats(&splats);
+    assert_eq!(film.pixels[4].weight, 1.25);
+    assert_eq!(film.pixels[4].color(), [0.8, 0.8, 1.0]);
+    assert_eq!(film.pixels[4].aovs.emission, [1.0; 3]);
+    assert_eq!(film.pixels[4].aovs.direct, [0.0, 0.0, 0.25]);
+    for i in [0, 1, 3] {
+        assert_eq!(film.pixels[i].weight, 0.25);
+        assert_eq!(film.pixels[i].color(), [0.0, 0.0, 1.0]);
+    }
+    assert_eq!(film.pixels[8].weight, 0.0);
+}
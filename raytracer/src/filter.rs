@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+fn half() -> f64 {
+    0.5
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+fn one_and_a_half() -> f64 {
+    1.5
+}
+
+fn two() -> f64 {
+    2.0
+}
+
+fn third() -> f64 {
+    1.0 / 3.0
+}
+
+// How samples are weighted into the pixels around them. Each sample adds to
+// every pixel whose center is within radius (in pixels) of it, horizontally
+// and vertically, and each pixel is the weighted average of the samples it
+// received.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PixelFilter {
+    // Every sample within radius counts the same. With the default radius,
+    // each pixel is the plain average of its own samples.
+    Box {
+        #[serde(default = "half")]
+        radius: f64,
+    },
+    // Weights fall linearly to zero at radius.
+    Tent {
+        #[serde(default = "one")]
+        radius: f64,
+    },
+    // A Gaussian with standard deviation sigma, shifted down to reach zero
+    // at radius.
+    Gaussian {
+        #[serde(default = "one_and_a_half")]
+        radius: f64,
+        #[serde(default = "half")]
+        sigma: f64,
+    },
+    // The Mitchell-Netravali cubic, stretched over radius. Its negative lobes
+    // sharpen edges, and b and c trade blurring against ringing.
+    Mitchell {
+        #[serde(default = "two")]
+        radius: f64,
+        #[serde(default = "third")]
+        b: f64,
+        #[serde(default = "third")]
+        c: f64,
+    },
+    // A sinc windowed by a wider sinc, with radius lobes. The sharpest of
+    // the filters, but it can ring around very bright edges.
+    Lanczos {
+        #[serde(default = "two")]
+        radius: f64,
+    },
+}
+
+impl Default for PixelFilter {
+    fn default() -> Self {
+        PixelFilter::Box { radius: half() }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl PixelFilter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            PixelFilter::Box { radius }
+            | PixelFilter::Tent { radius }
+            | PixelFilter::Gaussian { radius, .. }
+            | PixelFilter::Mitchell { radius, .. }
+            | PixelFilter::Lanczos { radius } => radius,
+        }
+    }
+
+    // The number of pixels on each side of a sample's own pixel that it can
+    // reach.
+    pub fn reach(&self) -> usize {
+        (self.radius() - 0.5).ceil().max(0.0) as usize
+    }
+
+    // The weight of a sample at an offset (dx, dy) from a pixel center.
+    // Rendering weighs each axis separately.
+    #[cfg(test)]
+    pub fn weight(&self, dx: f64, dy: f64) -> f64 {
+        self.evaluate(dx) * self.evaluate(dy)
+    }
+
+    // The weight along one axis.
+    pub fn evaluate(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.0;
+        }
+        match *self {
+            PixelFilter::Box { .. } => 1.0,
+            PixelFilter::Tent { radius } => radius - x,
+            PixelFilter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            PixelFilter::Mitchell { radius, b, c } => {
+                let x = 2.0 * x / radius;
+                let value = if x > 1.0 {
+                    (-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c)
+                } else {
+                    (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b)
+                };
+                value / 6.0
+            }
+            PixelFilter::Lanczos { radius } => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+#[test]
+fn test_pixel_filters() {
+    let box_filter = PixelFilter::default();
+    assert_eq!(box_filter.reach(), 0);
+    assert_eq!(box_filter.weight(0.3, -0.5), 1.0);
+    assert_eq!(box_filter.weight(0.6, 0.0), 0.0);
+
+    let tent = PixelFilter::Tent { radius: 1.0 };
+    assert_eq!(tent.reach(), 1);
+    assert_approx_eq!(tent.weight(0.5, 0.0), 0.5);
+    assert_approx_eq!(tent.weight(0.5, 0.5), 0.25);
+
+    let gaussian: PixelFilter = serde_json::from_str("{\"Gaussian\":{}}").unwrap();
+    assert_eq!(gaussian.reach(), 1);
+    assert!(gaussian.weight(0.0, 0.0) > gaussian.weight(0.5, 0.0));
+    assert_eq!(gaussian.weight(1.5, 0.0), 0.0);
+
+    let mitchell: PixelFilter = serde_json::from_str("{\"Mitchell\":{}}").unwrap();
+    assert_eq!(mitchell.reach(), 2);
+    assert_approx_eq!(mitchell.weight(0.0, 0.0), (8.0f64 / 9.0).powi(2));
+    assert!(mitchell.weight(1.5, 0.0) < 0.0);
+    assert_approx_eq!(mitchell.weight(2.0, 0.0), 0.0);
+
+    let lanczos = PixelFilter::Lanczos { radius: 3.0 };
+    assert_eq!(lanczos.reach(), 3);
+    assert_approx_eq!(lanczos.weight(0.0, 0.0), 1.0);
+    assert_approx_eq!(lanczos.weight(1.0, 0.0), 0.0);
+    assert!(lanczos.weight(1.5, 0.0) < 0.0);
+}
@@ -1,7 +1,31 @@
+pub mod adaptive;
+pub mod aov;
+pub mod cache;
 pub mod camera;
+pub mod checkpoint;
 pub mod config;
+pub mod denoise;
+pub mod film;
+pub mod filter;
 pub mod materials;
+pub mod merl;
+pub mod outline;
+pub mod output;
 pub mod point3d;
 pub mod ray;
 pub mod raytracer;
+pub mod rng;
+pub mod sampler;
 pub mod sphere;
+pub mod textures;
+
+// A file name in the temporary directory for a test to write to. It holds
+// the process ID, so that test runs sharing a machine do not collide.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> String {
+    let name = format!("raytracer_{}_{}", std::process::id(), name);
+    std::env::temp_dir()
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
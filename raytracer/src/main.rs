@@ -1,20 +1,110 @@
 use std::env;
 use std::fs;
+use std::time::Duration;
 
 use raytracer::config::Config;
-use raytracer::raytracer::render;
+use raytracer::raytracer::render_with_options;
+use raytracer::raytracer::RenderOptions;
+
+fn usage(program: &str) {
+    println!(
+        "Usage: {} [--seed <n>] [--time-limit <seconds>] [--target-noise <error>] \
+         [--max-samples <n>] [--checkpoint <file> [--checkpoint-interval <seconds>] [--resume]] \
+         <config_file> <output_file>",
+        program
+    );
+}
+
+// A number of seconds, which must not be negative.
+fn parse_seconds(arg: &str) -> Option<Duration> {
+    let seconds: f64 = arg.parse().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+// A noise target, which must be finite and positive to ever be met.
+fn parse_noise(arg: &str) -> Option<f64> {
+    let noise: f64 = arg.parse().ok()?;
+    (noise.is_finite() && noise > 0.0).then_some(noise)
+}
+
+// A number of samples per pixel, which must not be zero.
+fn parse_samples(arg: &str) -> Option<u32> {
+    arg.parse().ok().filter(|&samples| samples > 0)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        println!("Usage: {} <config_file> <output_file>", args[0]);
+    let mut options = RenderOptions::default();
+    let mut seed = None;
+    let mut files = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--time-limit" if i + 1 < args.len() => {
+                let Some(time_limit) = parse_seconds(&args[i + 1]) else {
+                    usage(&args[0]);
+                    return;
+                };
+                options.time_limit = Some(time_limit);
+                i += 1;
+            }
+            "--target-noise" if i + 1 < args.len() => {
+                let Some(target_noise) = parse_noise(&args[i + 1]) else {
+                    usage(&args[0]);
+                    return;
+                };
+                options.target_noise = Some(target_noise);
+                i += 1;
+            }
+            "--max-samples" if i + 1 < args.len() => {
+                let Some(max_samples) = parse_samples(&args[i + 1]) else {
+                    usage(&args[0]);
+                    return;
+                };
+                options.max_samples = Some(max_samples);
+                i += 1;
+            }
+            "--checkpoint" if i + 1 < args.len() => {
+                options.checkpoint = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--checkpoint-interval" if i + 1 < args.len() => {
+                let Some(interval) = parse_seconds(&args[i + 1]) else {
+                    usage(&args[0]);
+                    return;
+                };
+                options.checkpoint_interval = interval;
+                i += 1;
+            }
+            "--resume" => options.resume = true,
+            "--seed" if i + 1 < args.len() => {
+                let Ok(value) = args[i + 1].parse() else {
+                    usage(&args[0]);
+                    return;
+                };
+                seed = Some(value);
+                i += 1;
+            }
+            arg if arg.starts_with("--") => {
+                usage(&args[0]);
+                return;
+            }
+            arg => files.push(arg.to_string()),
+        }
+        i += 1;
+    }
+    if files.len() != 2 || (options.resume && options.checkpoint.is_none()) {
+        usage(&args[0]);
         return;
     }
 
-    let json = fs::read(&args[1]).expect("Unable to read config file.");
-    let scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse config json");
+    let json = fs::read(&files[0]).expect("Unable to read config file.");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse config json");
+    if let Some(seed) = seed {
+        scene.seed = seed;
+    }
 
-    let filename = &args[2]; //format!("{}_{:0>3}.png", args[2], i);
+    let filename = &files[1];
     println!("\nRendering {}", filename);
-    render(&filename, scene);
+    render_with_options(filename, scene, &options);
 }
@@ -1,22 +1,37 @@
-use jpeg_decoder::Decoder;
 use palette::Srgb;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
-use std::fs::File;
-use std::io::BufReader;
+use std::sync::Arc;
 
+use crate::merl::MerlBrdf;
 use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::textures::is_default;
+use crate::textures::luminance;
+use crate::textures::Filter;
+use crate::textures::MipMap;
+use crate::textures::TextureRef;
+use crate::textures::WrapMode;
+
+#[cfg(test)]
+use crate::textures::Procedural;
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
 
 pub trait Scatterable {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)>;
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)>;
 }
 
 // https://docs.rs/serde_with/1.9.4/serde_with/macro.serde_conv.html
 serde_with::serde_conv!(
-    SrgbAsArray,
+    pub SrgbAsArray,
     Srgb,
     |srgb: &Srgb| [srgb.red, srgb.green, srgb.blue],
     |value: [f32; 3]| -> Result<_, std::convert::Infallible> {
@@ -24,12 +39,13 @@ serde_with::serde_conv!(
     }
 );
 
-// TODO: replace this with the more elegant implementation in config.rs
+// Texture images are saved as the path they were loaded from, so that
+// changing the image changes the scene's JSON and its checkpoint fingerprint.
 serde_with::serde_conv!(
     TexturePixelsAsPath,
-    Vec<u8>,
-    |_pixels: &Vec<u8>| "/tmp/texture.jpg",
-    |value: &str| -> Result<_, std::convert::Infallible> { Ok(load_texture_image(value).0) }
+    Arc<MipMap>,
+    |pixels: &Arc<MipMap>| pixels.path().to_string(),
+    |value: &str| -> Result<_, std::convert::Infallible> { Ok(MipMap::load(value, false)) }
 );
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -39,87 +55,538 @@ pub enum Material {
     Glass(Glass),
     Texture(Texture),
     Light(Light),
+    Mix(Mix),
+    Layered(Layered),
+    Subsurface(Subsurface),
+    Cutout(Cutout),
+    AnisotropicMetal(AnisotropicMetal),
+    Hair(Hair),
+    Toon(Toon),
+    Measured(Measured),
+    Translucent(Translucent),
 }
 
 impl Scatterable for Material {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
         match self {
-            Material::Lambertian(l) => l.scatter(ray, hit_record),
-            Material::Metal(m) => m.scatter(ray, hit_record),
-            Material::Glass(g) => g.scatter(ray, hit_record),
-            Material::Texture(t) => t.scatter(ray, hit_record),
-            Material::Light(l) => l.scatter(ray, hit_record),
+            Material::Lambertian(l) => l.scatter(ray, hit_record, rng),
+            Material::Metal(m) => m.scatter(ray, hit_record, rng),
+            Material::Glass(g) => g.scatter(ray, hit_record, rng),
+            Material::Texture(t) => t.scatter(ray, hit_record, rng),
+            Material::Light(l) => l.scatter(ray, hit_record, rng),
+            Material::Mix(m) => m.scatter(ray, hit_record, rng),
+            Material::Layered(l) => l.scatter(ray, hit_record, rng),
+            Material::Subsurface(s) => s.scatter(ray, hit_record, rng),
+            Material::Cutout(c) => c.material.scatter(ray, hit_record, rng),
+            Material::AnisotropicMetal(m) => m.scatter(ray, hit_record, rng),
+            Material::Hair(h) => h.scatter(ray, hit_record, rng),
+            Material::Toon(t) => t.scatter(ray, hit_record, rng),
+            Material::Measured(m) => m.scatter(ray, hit_record, rng),
+            Material::Translucent(t) => t.scatter(ray, hit_record, rng),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-pub struct Light {}
+impl Material {
+    // Whether rays should pass straight through the surface at this hit, as
+    // if it was not there. Primitives check this before reporting a hit.
+    // Cutouts inside other materials count too. A mix is cut out where the
+    // material it mostly uses is.
+    pub fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        match self {
+            Material::Cutout(c) => {
+                c.opacity.opacity(hit_record) < c.threshold || c.material.is_cut_out(hit_record)
+            }
+            Material::Mix(m) => {
+                if m.factor.scalar(hit_record) < 0.5 {
+                    m.first.is_cut_out(hit_record)
+                } else {
+                    m.second.is_cut_out(hit_record)
+                }
+            }
+            Material::Layered(l) => l.base.is_cut_out(hit_record),
+            _ => false,
+        }
+    }
+
+    // The overall color of the surface at a hit, for the denoiser to tell
+    // texture detail from noise. Clear and emissive surfaces are white.
+    pub fn albedo(&self, hit_record: &HitRecord) -> Srgb {
+        match self {
+            Material::Lambertian(l) => l.albedo.color(hit_record),
+            Material::Metal(m) => match &m.conductor {
+                Some(conductor) => conductor.tinted_reflectance(1.0, m.albedo.color(hit_record)),
+                None => m.albedo.color(hit_record),
+            },
+            Material::Glass(_) | Material::Light(_) => Srgb::new(1.0, 1.0, 1.0),
+            Material::Texture(t) => t.get_albedo(hit_record.u, hit_record.v, hit_record.footprint),
+            Material::Mix(m) => {
+                let factor = m.factor.scalar(hit_record) as f32;
+                let (first, second) = (m.first.albedo(hit_record), m.second.albedo(hit_record));
+                Srgb::new(
+                    first.red + (second.red - first.red) * factor,
+                    first.green + (second.green - first.green) * factor,
+                    first.blue + (second.blue - first.blue) * factor,
+                )
+            }
+            Material::Layered(l) => l.base.albedo(hit_record),
+            Material::Subsurface(s) => s.albedo.color(hit_record),
+            Material::Cutout(c) => c.material.albedo(hit_record),
+            Material::AnisotropicMetal(m) => m.albedo.color(hit_record),
+            Material::Hair(h) => h.albedo.color(hit_record),
+            Material::Toon(t) => t.albedo.color(hit_record),
+            Material::Measured(m) => {
+                // Away from the specular peak.
+                let d = std::f64::consts::FRAC_1_SQRT_2;
+                let (wi, wo) = (Point3D::new(d, 0.0, d), Point3D::new(0.0, d, d));
+                let [r, g, b] = m
+                    .data
+                    .eval(&wi, &wo)
+                    .map(|c| (c * std::f64::consts::PI).clamp(0.0, 1.0) as f32);
+                Srgb::new(r, g, b)
+            }
+            Material::Translucent(t) => t.albedo.color(hit_record),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Light {
+    #[serde(default = "white")]
+    pub emission: TextureRef,
+}
+
+fn white() -> TextureRef {
+    Srgb::new(1.0, 1.0, 1.0).into()
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Light {
     pub fn new() -> Light {
-        Light {}
+        Light { emission: white() }
     }
 }
 
 impl Scatterable for Light {
-    fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        Some((None, Srgb::new(1.0, 1.0, 1.0)))
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        hit_record: &HitRecord,
+        _rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let emission = self.emission.color(hit_record);
+        Some((None, emission))
     }
 }
 
-#[serde_with::serde_as]
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Lambertian {
-    #[serde_as(as = "SrgbAsArray")]
-    pub albedo: Srgb,
+    pub albedo: TextureRef,
 }
 
 impl Lambertian {
     pub fn new(albedo: Srgb) -> Lambertian {
-        Lambertian { albedo }
+        Lambertian {
+            albedo: albedo.into(),
+        }
     }
 }
 
 impl Scatterable for Lambertian {
-    fn scatter(&self, _ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let mut scatter_direction = hit_record.normal + Point3D::random_in_unit_sphere();
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let mut scatter_direction = hit_record.normal + Point3D::random_in_unit_sphere(rng);
         if scatter_direction.near_zero() {
             scatter_direction = hit_record.normal;
         }
         let target = hit_record.point + scatter_direction;
         let scattered = Ray::new(hit_record.point, target - hit_record.point);
-        let attenuation = self.albedo;
+        let attenuation = self.albedo.color(hit_record);
         Some((Some(scattered), attenuation))
     }
 }
 
-#[serde_with::serde_as]
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+// A diffuse surface that also lets light through, scattering it diffusely
+// out of the other side, for leaves, paper and lampshades. On thin objects
+// light is diffused by each sheet it passes through. A solid object is
+// treated as a diffusing skin: light is diffused on the way in, and leaves
+// through the inside of the skin without being diffused again.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Translucent {
+    pub albedo: TextureRef,
+    pub transmission: TextureRef,
+}
+
+impl Translucent {
+    pub fn new(albedo: Srgb, transmission: Srgb) -> Translucent {
+        Translucent {
+            albedo: albedo.into(),
+            transmission: transmission.into(),
+        }
+    }
+}
+
+impl Scatterable for Translucent {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        if !hit_record.thin && !hit_record.front_face {
+            let scattered = Ray::new(hit_record.point, ray.direction);
+            return Some((Some(scattered), Srgb::new(1.0, 1.0, 1.0)));
+        }
+        let albedo = self.albedo.color(hit_record);
+        let transmission = self.transmission.color(hit_record);
+        let (reflect_weight, transmit_weight) = (luminance(albedo), luminance(transmission));
+        if reflect_weight + transmit_weight <= 0.0 {
+            return None;
+        }
+        let p_transmit = transmit_weight / (reflect_weight + transmit_weight);
+        let (side, color, p) = if rng.gen::<f64>() < p_transmit {
+            (-hit_record.normal, transmission, p_transmit)
+        } else {
+            (hit_record.normal, albedo, 1.0 - p_transmit)
+        };
+        let mut direction = side + Point3D::random_in_unit_sphere(rng);
+        if direction.near_zero() {
+            direction = side;
+        }
+        let scale = (1.0 / p) as f32;
+        let attenuation = Srgb::new(color.red * scale, color.green * scale, color.blue * scale);
+        Some((Some(Ray::new(hit_record.point, direction)), attenuation))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Metal {
-    #[serde_as(as = "SrgbAsArray")]
-    pub albedo: Srgb,
-    pub fuzz: f64,
+    // With a conductor, albedo tints the measured reflectance.
+    #[serde(default = "white")]
+    pub albedo: TextureRef,
+    pub fuzz: TextureRef,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coating: Option<ThinFilm>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conductor: Option<Conductor>,
+    // How much of the surface is metal, from 0 to 1, for painted or rusty
+    // metal. The rest scatters diffusely with the albedo. Fully metal if
+    // not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic: Option<TextureRef>,
 }
 
 impl Metal {
     pub fn new(albedo: Srgb, fuzz: f64) -> Metal {
-        Metal { albedo, fuzz }
+        Metal {
+            albedo: albedo.into(),
+            fuzz: fuzz.into(),
+            coating: None,
+            conductor: None,
+            metallic: None,
+        }
+    }
+
+    pub fn new_conductor(conductor: Conductor, fuzz: f64) -> Metal {
+        Metal {
+            albedo: white(),
+            fuzz: fuzz.into(),
+            coating: None,
+            conductor: Some(conductor),
+            metallic: None,
+        }
+    }
+}
+
+// Metals with measured complex indices of refraction.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ConductorPreset {
+    Gold,
+    Copper,
+    Silver,
+    Aluminium,
+    Chrome,
+}
+
+// The complex index of refraction (eta + ik) of a conductor, either a named
+// preset or per channel values at RGB_WAVELENGTHS.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Conductor {
+    Preset(ConductorPreset),
+    Measured { eta: [f64; 3], k: [f64; 3] },
+}
+
+impl Conductor {
+    pub fn eta_k(&self) -> ([f64; 3], [f64; 3]) {
+        match self {
+            Conductor::Preset(ConductorPreset::Gold) => {
+                ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603])
+            }
+            Conductor::Preset(ConductorPreset::Copper) => {
+                ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142])
+            }
+            Conductor::Preset(ConductorPreset::Silver) => {
+                ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147])
+            }
+            Conductor::Preset(ConductorPreset::Aluminium) => {
+                ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837])
+            }
+            Conductor::Preset(ConductorPreset::Chrome) => {
+                ([3.200, 2.850, 2.500], [3.300, 3.340, 3.300])
+            }
+            Conductor::Measured { eta, k } => (*eta, *k),
+        }
+    }
+
+    // Unpolarised Fresnel reflectance for light arriving from air at cos_i
+    // to the normal.
+    pub fn reflectance(&self, cos_i: f64) -> Srgb {
+        let (eta, k) = self.eta_k();
+        let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+        let sin2 = 1.0 - cos2;
+        let rgb: [f64; 3] = std::array::from_fn(|i| {
+            let (eta2, k2) = (eta[i] * eta[i], k[i] * k[i]);
+            let t0 = eta2 - k2 - sin2;
+            let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+            let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+            let t1 = a2_plus_b2 + cos2;
+            let t2 = 2.0 * cos_i.clamp(0.0, 1.0) * a;
+            let rs = (t1 - t2) / (t1 + t2);
+            let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+            let t4 = t2 * sin2;
+            let rp = rs * (t3 - t4) / (t3 + t4);
+            0.5 * (rs + rp)
+        });
+        Srgb::new(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32)
+    }
+
+    // The reflectance tinted by albedo.
+    fn tinted_reflectance(&self, cos_i: f64, albedo: Srgb) -> Srgb {
+        let f = self.reflectance(cos_i);
+        Srgb::new(
+            f.red * albedo.red,
+            f.green * albedo.green,
+            f.blue * albedo.blue,
+        )
     }
 }
 
+// A hit at the origin with a tangent along x, for testing how materials
+// scatter. normal must be perpendicular to x.
+#[cfg(test)]
+fn test_hit(normal: Point3D, front_face: bool, thin: bool) -> HitRecord<'static> {
+    static LIGHT: std::sync::OnceLock<Material> = std::sync::OnceLock::new();
+    let tangent = Point3D::new(1.0, 0.0, 0.0);
+    HitRecord {
+        t: 1.0,
+        point: Point3D::new(0.0, 0.0, 0.0),
+        normal,
+        tangent,
+        bitangent: normal.cross(&tangent),
+        front_face,
+        material: LIGHT.get_or_init(|| Material::Light(Light::new())),
+        u: 0.5,
+        v: 0.5,
+        footprint: 0.0,
+        thin,
+    }
+}
+
+#[test]
+fn test_conductor_reflectance() {
+    let gold = Conductor::Preset(ConductorPreset::Gold);
+    let r = gold.reflectance(1.0);
+    // Gold reflects red far more than blue.
+    assert!(r.red > 0.9 && r.blue < 0.5);
+    // All conductors become perfect mirrors at grazing angles.
+    for preset in [
+        ConductorPreset::Copper,
+        ConductorPreset::Silver,
+        ConductorPreset::Aluminium,
+        ConductorPreset::Chrome,
+    ] {
+        let r = Conductor::Preset(preset).reflectance(0.0);
+        assert_approx_eq!(r.green, 1.0, 1e-6);
+    }
+    // A conductor without absorption is a dielectric.
+    let dielectric = Conductor::Measured {
+        eta: [1.5; 3],
+        k: [0.0; 3],
+    };
+    assert_approx_eq!(dielectric.reflectance(1.0).red, 0.04, 1e-6);
+
+    let json = r#"{"fuzz":0.1,"conductor":"Gold"}"#;
+    let metal = serde_json::from_str::<Metal>(json).unwrap();
+    assert_eq!(metal.conductor, Some(gold));
+    let json = r#"{"albedo":[1.0,1.0,1.0],"fuzz":0.0,"conductor":{"eta":[1.0,1.0,1.0],"k":[2.0,2.0,2.0]}}"#;
+    let metal = serde_json::from_str::<Metal>(json).unwrap();
+    assert_eq!(json, serde_json::to_string(&metal).unwrap());
+}
+
+// Wavelengths in nanometres used to sample the red, green and blue channels
+// when evaluating wavelength dependent effects.
+const RGB_WAVELENGTHS: [f64; 3] = [650.0, 510.0, 475.0];
+
+// A thin dielectric film (e.g. a soap film or an anti-reflective lens
+// coating) sitting on top of a surface. Light reflected from the top and
+// bottom of the film interferes, so the reflectance varies per wavelength.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ThinFilm {
+    // Film thickness in nanometres.
+    pub thickness: f64,
+    pub index_of_refraction: f64,
+}
+
+// Fresnel amplitude reflection coefficients (s, p) for light travelling from
+// a medium of index eta_i into one of index eta_t.
+fn fresnel_amplitudes(cos_i: f64, cos_t: f64, eta_i: f64, eta_t: f64) -> (f64, f64) {
+    let rs = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    let rp = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    (rs, rp)
+}
+
+// Sum of all the reflections inside a film whose top and bottom interfaces
+// have amplitude reflection coefficients r12 and r23, where light travelling
+// down and back up the film picks up the given phase difference.
+fn airy_reflectance(r12: f64, r23: f64, phase: f64) -> f64 {
+    let cross = 2.0 * r12 * r23 * phase.cos();
+    (r12 * r12 + r23 * r23 + cross) / (1.0 + r12 * r12 * r23 * r23 + cross)
+}
+
+impl ThinFilm {
+    pub fn new(thickness: f64, index_of_refraction: f64) -> ThinFilm {
+        ThinFilm {
+            thickness,
+            index_of_refraction,
+        }
+    }
+
+    // Returns the cosine of the angle inside the film, or None if light
+    // arriving from eta_i is totally internally reflected by the film.
+    fn film_cosine(&self, cos_i: f64, eta_i: f64) -> Option<f64> {
+        let sin_f = eta_i / self.index_of_refraction * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        if sin_f > 1.0 {
+            None
+        } else {
+            Some((1.0 - sin_f * sin_f).sqrt())
+        }
+    }
+
+    fn reflectance_rgb(&self, cos_i: f64, eta_i: f64, cos_f: f64, r23: [(f64, f64); 3]) -> Srgb {
+        let eta_f = self.index_of_refraction;
+        let (r12s, r12p) = fresnel_amplitudes(cos_i, cos_f, eta_i, eta_f);
+        let mut rgb = [0.0; 3];
+        for (i, wavelength) in RGB_WAVELENGTHS.iter().enumerate() {
+            let phase = 4.0 * std::f64::consts::PI * eta_f * self.thickness * cos_f / wavelength;
+            let (r23s, r23p) = r23[i];
+            rgb[i] =
+                0.5 * (airy_reflectance(r12s, r23s, phase) + airy_reflectance(r12p, r23p, phase));
+        }
+        Srgb::new(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32)
+    }
+
+    // Reflectance of the film on top of a dielectric with index eta_t, for
+    // light arriving from a medium of index eta_i at cos_i to the normal.
+    pub fn reflectance_over_dielectric(&self, cos_i: f64, eta_i: f64, eta_t: f64) -> Srgb {
+        let cos_f = match self.film_cosine(cos_i, eta_i) {
+            Some(cos_f) => cos_f,
+            None => return Srgb::new(1.0, 1.0, 1.0),
+        };
+        let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let r23 = if sin_t > 1.0 {
+            (1.0, 1.0)
+        } else {
+            let cos_t = (1.0 - sin_t * sin_t).sqrt();
+            fresnel_amplitudes(cos_f, cos_t, self.index_of_refraction, eta_t)
+        };
+        self.reflectance_rgb(cos_i, eta_i, cos_f, [r23; 3])
+    }
+
+    // Reflectance of the film on top of a metal whose own reflectance is
+    // given by albedo. Metals reflect with a half-wave phase shift.
+    pub fn reflectance_over_metal(&self, cos_i: f64, eta_i: f64, albedo: Srgb) -> Srgb {
+        let cos_f = match self.film_cosine(cos_i, eta_i) {
+            Some(cos_f) => cos_f,
+            None => return Srgb::new(1.0, 1.0, 1.0),
+        };
+        let r23 = [albedo.red, albedo.green, albedo.blue].map(|c| {
+            let r = -(c as f64).max(0.0).sqrt();
+            (r, r)
+        });
+        self.reflectance_rgb(cos_i, eta_i, cos_f, r23)
+    }
+}
+
+#[test]
+fn test_thin_film_matching_base() {
+    // A film with the same index as the glass beneath it is invisible.
+    let film = ThinFilm::new(300.0, 1.5);
+    let r = film.reflectance_over_dielectric(1.0, 1.0, 1.5);
+    assert_approx_eq!(r.red, 0.04);
+    assert_approx_eq!(r.green, 0.04);
+    assert_approx_eq!(r.blue, 0.04);
+}
+
+#[test]
+fn test_thin_film_anti_reflective() {
+    // A quarter wave coating with index sqrt(1.5) cancels green reflections.
+    let n = 1.5_f64.sqrt();
+    let film = ThinFilm::new(RGB_WAVELENGTHS[1] / (4.0 * n), n);
+    let r = film.reflectance_over_dielectric(1.0, 1.0, 1.5);
+    assert!(r.green < 1e-6);
+    assert!(r.red > r.green && r.blue > r.green);
+}
+
 fn reflect(v: &Point3D, n: &Point3D) -> Point3D {
     *v - *n * (2.0 * v.dot(n))
 }
 
 impl Scatterable for Metal {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        if let Some(metallic) = &self.metallic {
+            if rng.gen::<f64>() >= metallic.scalar(hit_record) {
+                let diffuse = Lambertian {
+                    albedo: self.albedo.clone(),
+                };
+                return diffuse.scatter(ray, hit_record, rng);
+            }
+        }
         let reflected = reflect(&ray.direction, &hit_record.normal);
         let scattered = Ray::new(
             hit_record.point,
-            reflected + Point3D::random_in_unit_sphere() * self.fuzz,
+            reflected + Point3D::random_in_unit_sphere(rng) * self.fuzz.scalar(hit_record),
         );
-        let attenuation = self.albedo;
+        let cos_theta = (-ray.direction.unit_vector())
+            .dot(&hit_record.normal)
+            .min(1.0);
+        let mut albedo = self.albedo.color(hit_record);
+        if let Some(conductor) = &self.conductor {
+            albedo = conductor.tinted_reflectance(cos_theta, albedo);
+        }
+        let attenuation = match self.coating {
+            Some(film) => film.reflectance_over_metal(cos_theta, 1.0, albedo),
+            None => albedo,
+        };
         if scattered.direction.dot(&hit_record.normal) > 0.0 {
             Some((Some(scattered), attenuation))
         } else {
@@ -128,15 +595,18 @@ impl Scatterable for Metal {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Glass {
-    pub index_of_refraction: f64,
+    pub index_of_refraction: TextureRef,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coating: Option<ThinFilm>,
 }
 
 impl Glass {
     pub fn new(index_of_refraction: f64) -> Glass {
         Glass {
-            index_of_refraction,
+            index_of_refraction: index_of_refraction.into(),
+            coating: None,
         }
     }
 }
@@ -144,7 +614,7 @@ impl Glass {
 fn refract(uv: &Point3D, n: &Point3D, etai_over_etat: f64) -> Point3D {
     let cos_theta = ((-*uv).dot(n)).min(1.0);
     let r_out_perp = (*uv + *n * cos_theta) * etai_over_etat;
-    let r_out_parallel = *n * (-1.0 * (1.0 - r_out_perp.length_squared()).abs().sqrt());
+    let r_out_parallel = *n * -(1.0 - r_out_perp.length_squared()).abs().sqrt();
     r_out_perp + r_out_parallel
 }
 
@@ -174,18 +644,43 @@ fn test_reflectance() {
 }
 
 impl Scatterable for Glass {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let mut rng = rand::thread_rng();
-        let attenuation = Srgb::new(1.0 as f32, 1.0 as f32, 1.0 as f32);
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let attenuation = Srgb::new(1.0, 1.0, 1.0);
+        let index_of_refraction = self.index_of_refraction.scalar(hit_record);
         let refraction_ratio = if hit_record.front_face {
-            1.0 / self.index_of_refraction
+            1.0 / index_of_refraction
         } else {
-            self.index_of_refraction
+            index_of_refraction
         };
         let unit_direction = ray.direction.unit_vector();
         let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
+        if hit_record.thin {
+            return scatter_thin(
+                self,
+                index_of_refraction,
+                &unit_direction,
+                hit_record,
+                cos_theta,
+                rng,
+            );
+        }
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
+        if let (Some(film), false) = (self.coating, cannot_refract) {
+            return scatter_coated(
+                &film,
+                index_of_refraction,
+                &unit_direction,
+                hit_record,
+                cos_theta,
+                rng,
+            );
+        }
         if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f64>() {
             let reflected = reflect(&unit_direction, &hit_record.normal);
             let scattered = Ray::new(hit_record.point, reflected);
@@ -198,74 +693,896 @@ impl Scatterable for Glass {
     }
 }
 
+// A thin sheet of glass, such as a window pane or a bubble, bounces light
+// between its two faces but light passing through it leaves in the same
+// direction it arrived.
+fn scatter_thin(
+    glass: &Glass,
+    index_of_refraction: f64,
+    unit_direction: &Point3D,
+    hit_record: &HitRecord,
+    cos_theta: f64,
+    rng: &mut Sampler,
+) -> Option<(Option<Ray>, Srgb)> {
+    let r = match glass.coating {
+        Some(film) => film.reflectance_over_dielectric(cos_theta, 1.0, 1.0),
+        None => {
+            let r = reflectance(cos_theta, 1.0 / index_of_refraction) as f32;
+            let r = 2.0 * r / (1.0 + r);
+            Srgb::new(r, r, r)
+        }
+    };
+    scatter_with_reflectance(r, unit_direction, *unit_direction, hit_record, rng)
+}
+
+// Chooses between reflection and refraction using the average of the
+// film's per channel reflectance, then reweights each channel so the
+// expected color matches the film.
+fn scatter_coated(
+    film: &ThinFilm,
+    index_of_refraction: f64,
+    unit_direction: &Point3D,
+    hit_record: &HitRecord,
+    cos_theta: f64,
+    rng: &mut Sampler,
+) -> Option<(Option<Ray>, Srgb)> {
+    let (eta_i, eta_t) = if hit_record.front_face {
+        (1.0, index_of_refraction)
+    } else {
+        (index_of_refraction, 1.0)
+    };
+    let r = film.reflectance_over_dielectric(cos_theta, eta_i, eta_t);
+    let transmitted = refract(unit_direction, &hit_record.normal, eta_i / eta_t);
+    scatter_with_reflectance(r, unit_direction, transmitted, hit_record, rng)
+}
+
+// Reflects or transmits with a per channel reflectance r, choosing between
+// them using the average of r and reweighting each channel so the expected
+// color matches r.
+fn scatter_with_reflectance(
+    r: Srgb,
+    unit_direction: &Point3D,
+    transmitted: Point3D,
+    hit_record: &HitRecord,
+    rng: &mut Sampler,
+) -> Option<(Option<Ray>, Srgb)> {
+    let p = ((r.red + r.green + r.blue) / 3.0).clamp(1e-4, 1.0 - 1e-4);
+    if p > rng.gen::<f32>() {
+        let reflected = reflect(unit_direction, &hit_record.normal);
+        let attenuation = Srgb::new(r.red / p, r.green / p, r.blue / p);
+        Some((Some(Ray::new(hit_record.point, reflected)), attenuation))
+    } else {
+        let direction = transmitted;
+        let attenuation = Srgb::new(
+            (1.0 - r.red) / (1.0 - p),
+            (1.0 - r.green) / (1.0 - p),
+            (1.0 - r.blue) / (1.0 - p),
+        );
+        Some((Some(Ray::new(hit_record.point, direction)), attenuation))
+    }
+}
+
 #[serde_with::serde_as]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Texture {
     #[serde_as(as = "SrgbAsArray")]
     pub albedo: Srgb,
     #[serde_as(as = "TexturePixelsAsPath")]
-    pub pixels: Vec<u8>,
+    pub pixels: Arc<MipMap>,
     width: u64,
     height: u64,
     h_offset: f64,
-}
-
-fn load_texture_image(path: &str) -> (Vec<u8>, u64, u64) {
-    let file = File::open(path).expect(path);
-    let mut decoder = Decoder::new(BufReader::new(file));
-    let pixels = decoder.decode().expect("failed to decode image");
-    let metadata = decoder.info().unwrap();
-    (pixels, metadata.width as u64, metadata.height as u64)
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub filter: Filter,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub wrap: WrapMode,
 }
 
 impl Texture {
     pub fn new(albedo: Srgb, texture_path: &str, rot: f64) -> Texture {
-        let file = File::open(texture_path).expect("failed to open texture file");
-        let mut decoder = Decoder::new(BufReader::new(file));
-        let pixels = decoder.decode().expect("failed to decode image");
-        let metadata = decoder.info().unwrap();
+        let pixels = MipMap::load(texture_path, false);
         Texture {
             albedo,
+            width: pixels.width() as u64,
+            height: pixels.height() as u64,
             pixels,
-            width: metadata.width as u64,
-            height: metadata.height as u64,
             h_offset: rot,
+            filter: Filter::default(),
+            wrap: WrapMode::default(),
         }
     }
 
-    pub fn get_albedo(&self, u: f64, v: f64) -> Srgb {
-        let mut rot = u + self.h_offset;
-        if rot > 1.0 {
-            rot = rot - 1.0;
-        }
-        let uu = rot * (self.width) as f64;
-        let vv = (1.0 - v) * (self.height - 1) as f64;
-        let base_pixel =
-            (3 * ((vv.floor() as u64) * self.width as u64 + (uu.floor() as u64))) as usize;
-        let pixel_r = self.pixels[base_pixel];
-        let pixel_g = self.pixels[base_pixel + 1];
-        let pixel_b = self.pixels[base_pixel + 2];
-        Srgb::new(
-            pixel_r as f32 / 255.0,
-            pixel_g as f32 / 255.0,
-            pixel_b as f32 / 255.0,
-        )
+    pub fn get_albedo(&self, u: f64, v: f64, footprint: f64) -> Srgb {
+        self.pixels
+            .sample(u + self.h_offset, v, footprint, self.filter, self.wrap)
     }
 }
 
 impl Scatterable for Texture {
-    fn scatter(&self, _ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let mut scatter_direction = hit_record.normal + Point3D::random_in_unit_sphere();
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let mut scatter_direction = hit_record.normal + Point3D::random_in_unit_sphere(rng);
         if scatter_direction.near_zero() {
             scatter_direction = hit_record.normal;
         }
         let target = hit_record.point + scatter_direction;
         let scattered = Ray::new(hit_record.point, target - hit_record.point);
-        let attenuation = self.get_albedo(hit_record.u, hit_record.v);
+        let attenuation = self.get_albedo(hit_record.u, hit_record.v, hit_record.footprint);
         Some((Some(scattered), attenuation))
     }
 }
 
+// Blends two materials by randomly choosing one of them at each hit. The
+// second material is chosen with probability factor, which may be a
+// texture to use as a mask.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Mix {
+    pub first: Box<Material>,
+    pub second: Box<Material>,
+    pub factor: TextureRef,
+}
+
+impl Mix {
+    pub fn new(first: Material, second: Material, factor: f64) -> Mix {
+        Mix {
+            first: Box::new(first),
+            second: Box::new(second),
+            factor: factor.into(),
+        }
+    }
+}
+
+impl Scatterable for Mix {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let factor = self.factor.scalar(hit_record);
+        if factor > rng.gen::<f64>() {
+            self.second.scatter(ray, hit_record, rng)
+        } else {
+            self.first.scatter(ray, hit_record, rng)
+        }
+    }
+}
+
+// A clear dielectric coat over a base material, e.g. varnish over wood or
+// car paint. Light is reflected by the coat with the Fresnel reflectance,
+// and the remaining energy reaches the base material.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Layered {
+    pub base: Box<Material>,
+    pub index_of_refraction: TextureRef,
+    pub fuzz: TextureRef,
+}
+
+impl Layered {
+    pub fn new(base: Material, index_of_refraction: f64, fuzz: f64) -> Layered {
+        Layered {
+            base: Box::new(base),
+            index_of_refraction: index_of_refraction.into(),
+            fuzz: fuzz.into(),
+        }
+    }
+}
+
+impl Scatterable for Layered {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        if !hit_record.front_face {
+            return self.base.scatter(ray, hit_record, rng);
+        }
+        let unit_direction = ray.direction.unit_vector();
+        let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
+        let index_of_refraction = self.index_of_refraction.scalar(hit_record);
+        if reflectance(cos_theta, index_of_refraction) > rng.gen::<f64>() {
+            let reflected = reflect(&unit_direction, &hit_record.normal);
+            let scattered = Ray::new(
+                hit_record.point,
+                reflected + Point3D::random_in_unit_sphere(rng) * self.fuzz.scalar(hit_record),
+            );
+            if scattered.direction.dot(&hit_record.normal) > 0.0 {
+                return Some((Some(scattered), Srgb::new(1.0, 1.0, 1.0)));
+            }
+            return None;
+        }
+        self.base.scatter(ray, hit_record, rng)
+    }
+}
+
+// A translucent material (skin, marble, wax, milk) where light enters the
+// object and takes a random walk inside it before leaving. At each step the
+// light travels an exponentially distributed distance with the given mean
+// before scattering in a random direction, losing energy according to albedo.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Subsurface {
+    pub albedo: TextureRef,
+    pub mean_free_path: TextureRef,
+    pub index_of_refraction: TextureRef,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Srgb, mean_free_path: f64, index_of_refraction: f64) -> Subsurface {
+        Subsurface {
+            albedo: albedo.into(),
+            mean_free_path: mean_free_path.into(),
+            index_of_refraction: index_of_refraction.into(),
+        }
+    }
+}
+
+impl Scatterable for Subsurface {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let unit_direction = ray.direction.unit_vector();
+        if !hit_record.front_face {
+            // The ray is travelling inside the object. If it scatters before
+            // reaching the surface, start a new walk from the scattering point.
+            let distance = hit_record.t * ray.direction.length();
+            let mean_free_path = self.mean_free_path.scalar(hit_record);
+            let free_path = -mean_free_path * (1.0 - rng.gen::<f64>()).ln();
+            if free_path < distance {
+                let mut direction = Point3D::random_in_unit_sphere(rng);
+                if direction.near_zero() {
+                    direction = unit_direction;
+                }
+                let point = ray.origin + unit_direction * free_path;
+                let attenuation =
+                    self.albedo
+                        .value(hit_record.u, hit_record.v, &point, hit_record.footprint);
+                return Some((Some(Ray::new(point, direction)), attenuation));
+            }
+        }
+        let index_of_refraction = self.index_of_refraction.scalar(hit_record);
+        let refraction_ratio = if hit_record.front_face {
+            1.0 / index_of_refraction
+        } else {
+            index_of_refraction
+        };
+        let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let cannot_refract = refraction_ratio * sin_theta > 1.0;
+        let direction =
+            if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f64>() {
+                reflect(&unit_direction, &hit_record.normal)
+            } else {
+                refract(&unit_direction, &hit_record.normal, refraction_ratio)
+            };
+        let scattered = Ray::new(hit_record.point, direction);
+        Some((Some(scattered), Srgb::new(1.0, 1.0, 1.0)))
+    }
+}
+
+// Makes parts of another material fully transparent (leaves, fences,
+// decals). Wherever opacity is below threshold, rays pass through the
+// surface without scattering.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Cutout {
+    pub material: Box<Material>,
+    pub opacity: TextureRef,
+    #[serde(default = "half")]
+    pub threshold: f64,
+}
+
+fn half() -> f64 {
+    0.5
+}
+
+impl Cutout {
+    pub fn new(material: Material, opacity: TextureRef) -> Cutout {
+        Cutout {
+            material: Box::new(material),
+            opacity,
+            threshold: half(),
+        }
+    }
+}
+
+// An orthonormal (tangent, bitangent, normal) frame at a hit. The tangent is
+// re-orthogonalised in case a normal map has tilted the normal.
+fn tangent_frame(hit_record: &HitRecord) -> (Point3D, Point3D, Point3D) {
+    let n = hit_record.normal;
+    let mut t = hit_record.tangent - n * n.dot(&hit_record.tangent);
+    if t.near_zero() {
+        t = if n.x().abs() > 0.9 {
+            Point3D::new(0.0, 1.0, 0.0)
+        } else {
+            Point3D::new(1.0, 0.0, 0.0)
+        };
+        t = t - n * n.dot(&t);
+    }
+    let t = t.unit_vector();
+    (t, n.cross(&t), n)
+}
+
+// Smith masking function for anisotropic GGX, for a direction given in the
+// tangent frame.
+fn ggx_lambda(w: &Point3D, alpha_u: f64, alpha_v: f64) -> f64 {
+    let a2_tan2 =
+        (w.x() * w.x() * alpha_u * alpha_u + w.y() * w.y() * alpha_v * alpha_v) / (w.z() * w.z());
+    0.5 * (-1.0 + (1.0 + a2_tan2).sqrt())
+}
+
+// A brushed metal with separate GGX microfacet roughness along the
+// tangent (roughness_u) and bitangent (roughness_v) of the surface.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnisotropicMetal {
+    #[serde(default = "white")]
+    pub albedo: TextureRef,
+    pub roughness_u: TextureRef,
+    pub roughness_v: TextureRef,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conductor: Option<Conductor>,
+}
+
+impl AnisotropicMetal {
+    pub fn new(albedo: Srgb, roughness_u: f64, roughness_v: f64) -> AnisotropicMetal {
+        AnisotropicMetal {
+            albedo: albedo.into(),
+            roughness_u: roughness_u.into(),
+            roughness_v: roughness_v.into(),
+            conductor: None,
+        }
+    }
+}
+
+impl Scatterable for AnisotropicMetal {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let (t, b, n) = tangent_frame(hit_record);
+        let to_local = |w: &Point3D| Point3D::new(w.dot(&t), w.dot(&b), w.dot(&n));
+        let alpha_u = self.roughness_u.scalar(hit_record).max(1e-3);
+        let alpha_v = self.roughness_v.scalar(hit_record).max(1e-3);
+
+        let wo = to_local(&-ray.direction.unit_vector());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        // Sample a microfacet normal from the anisotropic GGX distribution.
+        let (xi1, xi2) = (rng.gen::<f64>(), rng.gen::<f64>());
+        let angle = 2.0 * std::f64::consts::PI * xi2;
+        let phi = (alpha_v * angle.sin()).atan2(alpha_u * angle.cos());
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let tan2_theta = xi1
+            / (1.0 - xi1).max(1e-12)
+            / (cos_phi * cos_phi / (alpha_u * alpha_u) + sin_phi * sin_phi / (alpha_v * alpha_v));
+        let cos_theta = 1.0 / (1.0 + tan2_theta).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let m = Point3D::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta);
+
+        let wo_dot_m = wo.dot(&m);
+        if wo_dot_m <= 0.0 {
+            return None;
+        }
+        let wi = m * (2.0 * wo_dot_m) - wo;
+        if wi.z() <= 0.0 {
+            return None;
+        }
+
+        // Monte Carlo weight for sampling proportional to D(m) (m.n). Without
+        // a conductor, Schlick's Fresnel approximation is used with albedo as
+        // the reflectance at normal incidence.
+        let g = 1.0 / (1.0 + ggx_lambda(&wo, alpha_u, alpha_v) + ggx_lambda(&wi, alpha_u, alpha_v));
+        let weight = (g * wo_dot_m / (wo.z() * m.z())) as f32;
+        let albedo = self.albedo.color(hit_record);
+        let fresnel = match &self.conductor {
+            Some(conductor) => conductor.tinted_reflectance(wo_dot_m, albedo),
+            None => {
+                let schlick = (1.0 - wo_dot_m).powi(5) as f32;
+                Srgb::new(
+                    albedo.red + (1.0 - albedo.red) * schlick,
+                    albedo.green + (1.0 - albedo.green) * schlick,
+                    albedo.blue + (1.0 - albedo.blue) * schlick,
+                )
+            }
+        };
+        let attenuation = Srgb::new(
+            weight * fresnel.red,
+            weight * fresnel.green,
+            weight * fresnel.blue,
+        );
+        let direction = t * wi.x() + b * wi.y() + n * wi.z();
+        Some((Some(Ray::new(hit_record.point, direction)), attenuation))
+    }
+}
+
+// Which surface direction the fibres of a Hair material run along.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub enum FibreDirection {
+    Tangent,
+    // From pole to pole on a sphere.
+    #[default]
+    Bitangent,
+}
+
+// Hair, fur and cloth made of fibres, after Kajiya and Kay. Light is either
+// reflected specularly off the fibre, leaving on a cone around the fibre at
+// the same angle it arrived, blurred by roughness, or scattered diffusely.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Hair {
+    pub albedo: TextureRef,
+    pub specular: TextureRef,
+    pub roughness: TextureRef,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub direction: FibreDirection,
+}
+
+impl Hair {
+    pub fn new(albedo: Srgb, specular: Srgb, roughness: f64) -> Hair {
+        Hair {
+            albedo: albedo.into(),
+            specular: specular.into(),
+            roughness: roughness.into(),
+            direction: FibreDirection::default(),
+        }
+    }
+}
+
+impl Scatterable for Hair {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let (t, b, n) = tangent_frame(hit_record);
+        let fibre = match self.direction {
+            FibreDirection::Tangent => t,
+            FibreDirection::Bitangent => b,
+        };
+        let albedo = self.albedo.color(hit_record);
+        let specular = self.specular.color(hit_record);
+        let (diffuse_weight, specular_weight) = (luminance(albedo), luminance(specular));
+        if diffuse_weight + specular_weight <= 0.0 {
+            return None;
+        }
+        let p_specular = specular_weight / (diffuse_weight + specular_weight);
+
+        if rng.gen::<f64>() >= p_specular {
+            let mut direction = n + Point3D::random_in_unit_sphere(rng);
+            if direction.near_zero() {
+                direction = n;
+            }
+            let scale = (1.0 / (1.0 - p_specular)) as f32;
+            let attenuation = Srgb::new(
+                albedo.red * scale,
+                albedo.green * scale,
+                albedo.blue * scale,
+            );
+            return Some((Some(Ray::new(hit_record.point, direction)), attenuation));
+        }
+
+        // Keep the angle to the fibre, jittered by roughness, and pick a
+        // random direction around the fibre on the lit side.
+        let d = ray.direction.unit_vector();
+        let roughness = self.roughness.scalar(hit_record);
+        let theta = d.dot(&fibre).clamp(-1.0, 1.0).asin()
+            + (rng.gen::<f64>() - 0.5) * roughness * std::f64::consts::PI;
+        let around = (n - fibre * n.dot(&fibre)).unit_vector();
+        let side = fibre.cross(&around);
+        let phi = (rng.gen::<f64>() - 0.5) * std::f64::consts::PI;
+        let direction = fibre * theta.sin() + (around * phi.cos() + side * phi.sin()) * theta.cos();
+        if direction.dot(&n) <= 0.0 {
+            return None;
+        }
+        let scale = (1.0 / p_specular) as f32;
+        let attenuation = Srgb::new(
+            specular.red * scale,
+            specular.green * scale,
+            specular.blue * scale,
+        );
+        Some((Some(Ray::new(hit_record.point, direction)), attenuation))
+    }
+}
+
+fn three() -> u32 {
+    3
+}
+
+// Cartoon style shading: the diffuse lighting is quantised into a few flat
+// bands of the albedo color. The lighting is worked out by the renderer,
+// which calls shade() with the summed diffuse term from the scene's lights.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Toon {
+    pub albedo: TextureRef,
+    #[serde(default = "three")]
+    pub bands: u32,
+}
+
+impl Toon {
+    pub fn new(albedo: Srgb, bands: u32) -> Toon {
+        Toon {
+            albedo: albedo.into(),
+            bands,
+        }
+    }
+
+    // Parts of the surface in shadow or facing away from every light get
+    // the darkest band rather than black.
+    pub fn shade(&self, hit_record: &HitRecord, diffuse: f64) -> Srgb {
+        let bands = self.bands.max(1) as f64;
+        let band = ((diffuse.clamp(0.0, 1.0) * bands).floor() + 1.0).min(bands);
+        let level = (band / bands) as f32;
+        let albedo = self.albedo.color(hit_record);
+        Srgb::new(
+            albedo.red * level,
+            albedo.green * level,
+            albedo.blue * level,
+        )
+    }
+}
+
+impl Scatterable for Toon {
+    // Without lighting information from the renderer, a toon surface is
+    // flat shaded at full brightness.
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        hit_record: &HitRecord,
+        _rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        Some((None, self.shade(hit_record, 1.0)))
+    }
+}
+
+#[test]
+fn test_toon_bands() {
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, false);
+    let toon = Toon::new(Srgb::new(0.9, 0.6, 0.3), 3);
+    let dark = toon.shade(&hit_record, 0.0);
+    assert_approx_eq!(dark.red, 0.3, 1e-6);
+    assert_eq!(toon.shade(&hit_record, 0.1), dark);
+    assert_eq!(toon.shade(&hit_record, 0.5), toon.shade(&hit_record, 0.6));
+    assert_eq!(toon.shade(&hit_record, 0.9), Srgb::new(0.9, 0.6, 0.3));
+    assert_eq!(toon.shade(&hit_record, 2.0), Srgb::new(0.9, 0.6, 0.3));
+
+    let toon = serde_json::from_str::<Toon>(r#"{"albedo":[1.0,0.0,0.0]}"#).unwrap();
+    assert_eq!(toon.bands, 3);
+}
+
+// A measured BRDF loaded from a MERL .binary file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "MeasuredParams")]
+pub struct Measured {
+    pub brdf: String,
+    #[serde(skip_serializing)]
+    data: Arc<MerlBrdf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MeasuredParams {
+    pub brdf: String,
+}
+
+impl From<MeasuredParams> for Measured {
+    fn from(p: MeasuredParams) -> Self {
+        Measured::new(&p.brdf)
+    }
+}
+
+impl Measured {
+    pub fn new(path: &str) -> Measured {
+        Measured {
+            brdf: path.to_string(),
+            data: MerlBrdf::load(path),
+        }
+    }
+}
+
+impl Scatterable for Measured {
+    // Half of the samples are cosine weighted and half are drawn from a lobe
+    // around the mirror direction, and the result is weighted by the
+    // combined density of both strategies.
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut Sampler,
+    ) -> Option<(Option<Ray>, Srgb)> {
+        let (t, b, n) = tangent_frame(hit_record);
+        let wo = -ray.direction.unit_vector();
+        let wo = Point3D::new(wo.dot(&t), wo.dot(&b), wo.dot(&n));
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let mirror = Point3D::new(-wo.x(), -wo.y(), wo.z());
+        let exponent = self.data.lobe_exponent();
+
+        let (u1, u2) = (rng.gen::<f64>(), rng.gen::<f64>());
+        let phi = 2.0 * std::f64::consts::PI * u2;
+        let wi = if rng.gen::<bool>() {
+            let r = u1.sqrt();
+            Point3D::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).sqrt())
+        } else {
+            let cos_a = u1.powf(1.0 / (exponent + 1.0));
+            let sin_a = (1.0 - cos_a * cos_a).max(0.0).sqrt();
+            let helper = if mirror.x().abs() > 0.9 {
+                Point3D::new(0.0, 1.0, 0.0)
+            } else {
+                Point3D::new(1.0, 0.0, 0.0)
+            };
+            let x = helper.cross(&mirror).unit_vector();
+            let y = mirror.cross(&x);
+            x * (sin_a * phi.cos()) + y * (sin_a * phi.sin()) + mirror * cos_a
+        };
+        if wi.z() <= 0.0 {
+            return None;
+        }
+
+        let pdf_cosine = wi.z() / std::f64::consts::PI;
+        let pdf_lobe = (exponent + 1.0) / (2.0 * std::f64::consts::PI)
+            * wi.dot(&mirror).max(0.0).powf(exponent);
+        let weight = wi.z() / (0.5 * pdf_cosine + 0.5 * pdf_lobe);
+        let [r, g, bl] = self.data.eval(&wi, &wo);
+        let attenuation = Srgb::new(
+            (r * weight) as f32,
+            (g * weight) as f32,
+            (bl * weight) as f32,
+        );
+        let direction = t * wi.x() + b * wi.y() + n * wi.z();
+        Some((Some(Ray::new(hit_record.point, direction)), attenuation))
+    }
+}
+
+#[test]
+fn test_measured_lambertian() {
+    let mut rng = Sampler::independent(0);
+    // A measured Lambertian surface reflects its albedo on average.
+    let measured = Measured {
+        brdf: "lambertian.binary".to_string(),
+        data: Arc::new(MerlBrdf::from_fn(|_, _, _| {
+            [0.5 / std::f64::consts::PI, 0.25 / std::f64::consts::PI, 0.0]
+        })),
+    };
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, false);
+    let ray = Ray::new(Point3D::new(-1.0, 1.0, 0.0), Point3D::new(1.0, -1.0, 0.0));
+    let (mut red, mut green) = (0.0, 0.0);
+    for _ in 0..1000 {
+        if let Some((Some(scattered), attenuation)) = measured.scatter(&ray, &hit_record, &mut rng)
+        {
+            assert!(scattered.direction.y() > 0.0);
+            red += attenuation.red / 1000.0;
+            green += attenuation.green / 1000.0;
+        }
+    }
+    assert_approx_eq!(red, 0.5, 0.02);
+    assert_approx_eq!(green, 0.25, 0.01);
+    assert_eq!(
+        serde_json::to_string(&measured).unwrap(),
+        r#"{"brdf":"lambertian.binary"}"#
+    );
+}
+
+#[test]
+fn test_translucent() {
+    let mut rng = Sampler::independent(0);
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, true);
+    let ray = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(0.0, -1.0, 0.0));
+    let leaf = Translucent::new(Srgb::new(0.2, 0.4, 0.1), Srgb::new(0.2, 0.4, 0.1));
+    let (mut reflected, mut transmitted) = (0, 0);
+    for _ in 0..1000 {
+        let (scattered, attenuation) = leaf.scatter(&ray, &hit_record, &mut rng).unwrap();
+        assert_approx_eq!(attenuation.green, 0.8, 1e-6);
+        if scattered.unwrap().direction.y() > 0.0 {
+            reflected += 1;
+        } else {
+            transmitted += 1;
+        }
+    }
+    assert!(reflected > 400 && transmitted > 400);
+
+    // Light passes straight through thin glass.
+    let glass = Glass::new(1.5);
+    for _ in 0..20 {
+        let (scattered, _) = glass.scatter(&ray, &hit_record, &mut rng).unwrap();
+        let direction = scattered.unwrap().direction;
+        assert!(direction.y().abs() > 0.99 && direction.x().abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_translucent_solid() {
+    let mut rng = Sampler::independent(0);
+    let leaf = Translucent::new(Srgb::new(0.2, 0.4, 0.1), Srgb::new(0.2, 0.4, 0.1));
+    let ray = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(0.0, -1.0, 0.0));
+    let inside = |thin| test_hit(Point3D::new(0.0, 1.0, 0.0), false, thin);
+    // Leaving a solid, light passes through the skin undisturbed.
+    for _ in 0..20 {
+        let (scattered, attenuation) = leaf.scatter(&ray, &inside(false), &mut rng).unwrap();
+        assert_eq!(scattered.unwrap().direction, ray.direction);
+        assert_eq!(attenuation, Srgb::new(1.0, 1.0, 1.0));
+    }
+    // The inside of a thin shell is another sheet, which diffuses it again.
+    let scattered: Vec<Point3D> = (0..20)
+        .map(|_| {
+            leaf.scatter(&ray, &inside(true), &mut rng)
+                .unwrap()
+                .0
+                .unwrap()
+                .direction
+        })
+        .collect();
+    assert!(scattered.iter().any(|d| d.x().abs() > 0.1));
+}
+
+#[test]
+fn test_metallic() {
+    let mut rng = Sampler::independent(0);
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, false);
+    let ray = Ray::new(Point3D::new(-1.0, 1.0, 0.0), Point3D::new(1.0, -1.0, 0.0));
+    let mirrored = |metal: &Metal, rng: &mut Sampler| {
+        let (scattered, _) = metal.scatter(&ray, &hit_record, rng).unwrap();
+        let d = scattered.unwrap().direction.unit_vector();
+        (d.x() - 0.5_f64.sqrt()).abs() < 1e-6 && (d.y() - 0.5_f64.sqrt()).abs() < 1e-6
+    };
+    let mut metal = Metal::new(Srgb::new(0.8, 0.8, 0.8), 0.0);
+    assert!((0..20).all(|_| mirrored(&metal, &mut rng)));
+    // Half metal reflects like a mirror about half of the time.
+    metal.metallic = Some(0.5.into());
+    let mirror_count = (0..1000).filter(|_| mirrored(&metal, &mut rng)).count();
+    assert!((400..600).contains(&mirror_count));
+    metal.metallic = Some(0.0.into());
+    assert!((0..20).all(|_| !mirrored(&metal, &mut rng)));
+}
+
+#[test]
+fn test_nested_cutouts() {
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, false);
+    let solid = || Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5)));
+    let hole = || Material::Cutout(Cutout::new(solid(), 0.0.into()));
+    assert!(hole().is_cut_out(&hit_record));
+    assert!(!solid().is_cut_out(&hit_record));
+    let varnished = Material::Layered(Layered::new(hole(), 1.5, 0.0));
+    assert!(varnished.is_cut_out(&hit_record));
+    let opaque_outer = Material::Cutout(Cutout::new(hole(), 1.0.into()));
+    assert!(opaque_outer.is_cut_out(&hit_record));
+    let mostly_hole = Material::Mix(Mix::new(solid(), hole(), 0.75));
+    assert!(mostly_hole.is_cut_out(&hit_record));
+    let mostly_solid = Material::Mix(Mix::new(solid(), hole(), 0.25));
+    assert!(!mostly_solid.is_cut_out(&hit_record));
+}
+
+#[test]
+fn test_anisotropic_metal() {
+    let mut rng = Sampler::independent(0);
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, false);
+    let ray = Ray::new(Point3D::new(-1.0, 1.0, 0.0), Point3D::new(1.0, -1.0, 0.0));
+
+    // With almost no roughness this is a mirror.
+    let mirror = AnisotropicMetal::new(Srgb::new(1.0, 1.0, 1.0), 0.0, 0.0);
+    let (scattered, attenuation) = mirror.scatter(&ray, &hit_record, &mut rng).unwrap();
+    let direction = scattered.unwrap().direction.unit_vector();
+    assert_approx_eq!(direction.x(), 0.5_f64.sqrt(), 1e-2);
+    assert_approx_eq!(direction.y(), 0.5_f64.sqrt(), 1e-2);
+    assert_approx_eq!(attenuation.red, 1.0, 1e-2);
+
+    // Rough only along the tangent, reflections spread in x but not in z.
+    let brushed = AnisotropicMetal::new(Srgb::new(1.0, 1.0, 1.0), 0.5, 0.0);
+    for _ in 0..20 {
+        if let Some((Some(scattered), _)) = brushed.scatter(&ray, &hit_record, &mut rng) {
+            let d = scattered.direction.unit_vector();
+            assert!(d.y() > 0.0);
+            assert_approx_eq!(d.z(), 0.0, 1e-2);
+        }
+    }
+}
+
+#[test]
+fn test_hair() {
+    let mut rng = Sampler::independent(0);
+    let hit_record = test_hit(Point3D::new(0.0, 0.0, 1.0), true, false);
+    // Purely specular and smooth: light keeps its angle to the fibre.
+    let hair = Hair::new(Srgb::new(0.0, 0.0, 0.0), Srgb::new(0.5, 0.4, 0.3), 0.0);
+    let ray = Ray::new(Point3D::new(0.0, -1.0, 1.0), Point3D::new(0.0, 1.0, -1.0));
+    let d = ray.direction.unit_vector();
+    for _ in 0..20 {
+        if let Some((Some(scattered), attenuation)) = hair.scatter(&ray, &hit_record, &mut rng) {
+            let out = scattered.direction.unit_vector();
+            assert_approx_eq!(out.y(), d.y());
+            assert!(out.z() > 0.0);
+            assert_eq!(attenuation, Srgb::new(0.5, 0.4, 0.3));
+        }
+    }
+    let json = r#"{"albedo":[0.3,0.2,0.1],"specular":[0.5,0.5,0.5],"roughness":0.2,"direction":"Tangent"}"#;
+    let hair = serde_json::from_str::<Hair>(json).unwrap();
+    assert_eq!(hair.direction, FibreDirection::Tangent);
+    assert_eq!(json, serde_json::to_string(&hair).unwrap());
+}
+
+#[test]
+fn test_subsurface() {
+    let mut rng = Sampler::independent(0);
+    let milk = Subsurface::new(Srgb::new(0.9, 0.9, 0.9), 1e-9, 1.0);
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(0.0, 0.0, 1.0));
+    let hit_record = HitRecord {
+        point: Point3D::new(0.0, 0.0, 1.0),
+        ..test_hit(Point3D::new(0.0, 0.0, -1.0), false, false)
+    };
+    // A short mean free path scatters almost immediately inside the object.
+    let (scattered, attenuation) = milk.scatter(&ray, &hit_record, &mut rng).unwrap();
+    assert!(scattered.unwrap().origin.z() < 0.001);
+    assert_eq!(attenuation, Srgb::new(0.9, 0.9, 0.9));
+
+    // A long mean free path leaves the object without losing energy.
+    let clear = Subsurface::new(Srgb::new(0.9, 0.9, 0.9), 1e9, 1.0);
+    let (scattered, attenuation) = clear.scatter(&ray, &hit_record, &mut rng).unwrap();
+    let scattered = scattered.unwrap();
+    assert_eq!(scattered.origin, hit_record.point);
+    assert_approx_eq!(scattered.direction.z(), 1.0);
+    assert_eq!(attenuation, Srgb::new(1.0, 1.0, 1.0));
+}
+
+#[test]
+fn test_subsurface_solid_texture() {
+    let mut rng = Sampler::independent(0);
+    let mut marble = Subsurface::new(Srgb::new(0.0, 0.0, 0.0), 1e-9, 1.0);
+    marble.albedo = TextureRef::Procedural(Procedural::SolidChecker {
+        even: Srgb::new(1.0, 0.0, 0.0),
+        odd: Srgb::new(0.0, 0.0, 1.0),
+        scale: 1.0,
+    });
+    let ray = Ray::new(Point3D::new(0.5, 0.5, 0.0), Point3D::new(0.0, 0.0, 1.0));
+    let hit_record = HitRecord {
+        point: Point3D::new(0.5, 0.5, 1.0),
+        ..test_hit(Point3D::new(0.0, 0.0, -1.0), false, false)
+    };
+    // The color comes from where the ray scattered inside, not where it
+    // would have left.
+    let (_, attenuation) = marble.scatter(&ray, &hit_record, &mut rng).unwrap();
+    assert_eq!(attenuation, Srgb::new(1.0, 0.0, 0.0));
+}
+
+#[test]
+fn test_mix() {
+    let mut rng = Sampler::independent(0);
+    let red = Material::Lambertian(Lambertian::new(Srgb::new(1.0, 0.0, 0.0)));
+    let blue = Material::Lambertian(Lambertian::new(Srgb::new(0.0, 0.0, 1.0)));
+    let mix = Mix::new(red, blue, 0.0);
+    let hit_record = test_hit(Point3D::new(0.0, 1.0, 0.0), true, false);
+    let ray = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(0.0, -1.0, 0.0));
+    for _ in 0..10 {
+        let (_, attenuation) = mix.scatter(&ray, &hit_record, &mut rng).unwrap();
+        assert_eq!(attenuation, Srgb::new(1.0, 0.0, 0.0));
+    }
+    let mix = Mix::new(*mix.first, *mix.second, 1.0);
+    let (_, attenuation) = mix.scatter(&ray, &hit_record, &mut rng).unwrap();
+    assert_eq!(attenuation, Srgb::new(0.0, 0.0, 1.0));
+}
+
+#[test]
+fn test_layered_to_json() {
+    let l = Layered::new(
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        1.5,
+        0.0,
+    );
+    let serialized = serde_json::to_string(&l).unwrap();
+    assert_eq!(
+        r#"{"base":{"Lambertian":{"albedo":[0.5,0.5,0.5]}},"index_of_refraction":1.5,"fuzz":0.0}"#,
+        serialized,
+    );
+    let _ = serde_json::from_str::<Layered>(&serialized).unwrap();
+}
+
 #[test]
 fn test_texture() {
     let _world = Material::Texture(Texture::new(
@@ -281,3 +1598,33 @@ fn test_to_json() {
     let serialized = serde_json::to_string(&m).unwrap();
     assert_eq!(r#"{"albedo":[0.8,0.8,0.8],"fuzz":2.0}"#, serialized,);
 }
+
+#[test]
+fn test_coated_glass_to_json() {
+    let mut g = Glass::new(1.0);
+    g.coating = Some(ThinFilm::new(400.0, 1.33));
+    let serialized = serde_json::to_string(&g).unwrap();
+    assert_eq!(
+        r#"{"index_of_refraction":1.0,"coating":{"thickness":400.0,"index_of_refraction":1.33}}"#,
+        serialized,
+    );
+    let g = serde_json::from_str::<Glass>(r#"{"index_of_refraction":1.5}"#).unwrap();
+    assert!(g.coating.is_none());
+}
+
+#[test]
+fn test_textured_parameters_from_json() {
+    let json = r#"{"albedo":{"Checker":{"even":[0.9,0.6,0.2],"odd":[0.6,0.6,0.6],"scale":8.0}},"fuzz":{"Noise":{"color":[0.3,0.3,0.3],"scale":4.0,"octaves":3}}}"#;
+    let m = serde_json::from_str::<Metal>(json).unwrap();
+    assert!(matches!(m.albedo, TextureRef::Procedural(_)));
+    assert!(matches!(m.fuzz, TextureRef::Procedural(_)));
+    assert!(m.metallic.is_none());
+    let json = r#"{"albedo":[0.8,0.8,0.8],"fuzz":0.1,"metallic":{"image":"data/earth.jpg"}}"#;
+    let m = serde_json::from_str::<Metal>(json).unwrap();
+    assert!(matches!(m.metallic, Some(TextureRef::Image(_))));
+
+    let l = serde_json::from_str::<Light>("{}").unwrap();
+    assert!(matches!(l.emission, TextureRef::Color(_)));
+    let l = serde_json::from_str::<Light>(r#"{"emission":4.0}"#).unwrap();
+    assert!(matches!(l.emission, TextureRef::Value(_)));
+}
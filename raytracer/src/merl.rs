@@ -0,0 +1,245 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use crate::cache::file_key;
+use crate::cache::load_shared;
+use crate::cache::SharedCache;
+use crate::point3d::Point3D;
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+#[cfg(test)]
+use crate::temp_path;
+
+// Resolution of the MERL tables. Only half of the 360 degrees of phi_d are
+// stored, since the BRDF is unchanged by phi_d -> phi_d + pi.
+const THETA_H_RES: usize = 90;
+const THETA_D_RES: usize = 90;
+const PHI_D_RES: usize = 180;
+const SIZE: usize = THETA_H_RES * THETA_D_RES * PHI_D_RES;
+
+// The tables store each channel scaled by these factors.
+const SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// BRDFs loaded by MerlBrdf::load, by path. The tables are about 17MB each.
+static BRDF_CACHE: SharedCache<PathBuf, MerlBrdf> = OnceLock::new();
+
+// An isotropic BRDF measured by Matusik et al. for "A Data-Driven
+// Reflectance Model", in the MERL .binary format: three 32 bit dimensions
+// followed by tables of doubles for red, green and blue, indexed by the
+// half and difference angles of Rusinkiewicz's parameterisation.
+pub struct MerlBrdf {
+    values: Vec<f32>,
+    // Exponent of a cosine power lobe around the mirror direction that
+    // roughly follows the specular peak, used for importance sampling.
+    lobe_exponent: f64,
+}
+
+impl fmt::Debug for MerlBrdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MerlBrdf")
+            .field("lobe_exponent", &self.lobe_exponent)
+            .finish()
+    }
+}
+
+fn theta_h_index(theta_h: f64) -> usize {
+    // The half angle is sampled more densely near the specular peak.
+    if theta_h <= 0.0 {
+        return 0;
+    }
+    let degrees = theta_h.to_degrees();
+    ((degrees * THETA_H_RES as f64).sqrt() as usize).min(THETA_H_RES - 1)
+}
+
+fn theta_d_index(theta_d: f64) -> usize {
+    ((theta_d / std::f64::consts::FRAC_PI_2 * THETA_D_RES as f64).max(0.0) as usize)
+        .min(THETA_D_RES - 1)
+}
+
+fn phi_d_index(phi_d: f64) -> usize {
+    let phi_d = if phi_d < 0.0 {
+        phi_d + std::f64::consts::PI
+    } else {
+        phi_d
+    };
+    ((phi_d / std::f64::consts::PI * PHI_D_RES as f64).max(0.0) as usize).min(PHI_D_RES - 1)
+}
+
+fn index(theta_h: usize, theta_d: usize, phi_d: usize) -> usize {
+    phi_d + PHI_D_RES * (theta_d + THETA_D_RES * theta_h)
+}
+
+impl MerlBrdf {
+    // Loads a .binary file, or returns the tables already loaded from it.
+    pub fn load(path: &str) -> Arc<MerlBrdf> {
+        load_shared(&BRDF_CACHE, file_key(path), || {
+            let bytes = fs::read(path).expect(path);
+            MerlBrdf::from_bytes(&bytes).expect(path)
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MerlBrdf, String> {
+        if bytes.len() < 12 {
+            return Err("truncated MERL header".to_string());
+        }
+        let dims: Vec<usize> = bytes[..12]
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .collect();
+        if dims != [THETA_H_RES, THETA_D_RES, PHI_D_RES] {
+            return Err(format!("unexpected MERL dimensions {:?}", dims));
+        }
+        if bytes.len() != 12 + 3 * SIZE * 8 {
+            return Err("truncated MERL data".to_string());
+        }
+        let values = bytes[12..]
+            .chunks_exact(8)
+            .enumerate()
+            .map(|(i, b)| {
+                let value = f64::from_le_bytes(b.try_into().unwrap());
+                (value * SCALE[i / SIZE]).max(0.0) as f32
+            })
+            .collect();
+        Ok(MerlBrdf::from_values(values))
+    }
+
+    // Builds a BRDF from a function of (theta_h, theta_d, phi_d), mainly
+    // for testing.
+    pub fn from_fn(f: impl Fn(f64, f64, f64) -> [f64; 3]) -> MerlBrdf {
+        let mut values = vec![0.0; 3 * SIZE];
+        for h in 0..THETA_H_RES {
+            let theta_h = ((h * h) as f64 / THETA_H_RES as f64).to_radians();
+            for d in 0..THETA_D_RES {
+                let theta_d = (d as f64 + 0.5) / THETA_D_RES as f64 * std::f64::consts::FRAC_PI_2;
+                for p in 0..PHI_D_RES {
+                    let phi_d = (p as f64 + 0.5) / PHI_D_RES as f64 * std::f64::consts::PI;
+                    let rgb = f(theta_h, theta_d, phi_d);
+                    for (c, value) in rgb.iter().enumerate() {
+                        values[c * SIZE + index(h, d, p)] = *value as f32;
+                    }
+                }
+            }
+        }
+        MerlBrdf::from_values(values)
+    }
+
+    fn from_values(values: Vec<f32>) -> MerlBrdf {
+        let mut brdf = MerlBrdf {
+            values,
+            lobe_exponent: 1.0,
+        };
+        // Fit the lobe to the falloff of the BRDF between a half angle of 0
+        // and 10 degrees, where the mirror direction is 20 degrees away.
+        let peak = brdf.luminance(index(0, 0, 0));
+        let falloff = brdf.luminance(index(30, 0, 0));
+        if peak > 0.0 && falloff < peak {
+            let exponent = (falloff.max(1e-6) / peak).ln() / 20.0_f64.to_radians().cos().ln();
+            brdf.lobe_exponent = exponent.clamp(1.0, 10000.0);
+        }
+        brdf
+    }
+
+    fn luminance(&self, i: usize) -> f64 {
+        0.2126 * self.values[i] as f64
+            + 0.7152 * self.values[SIZE + i] as f64
+            + 0.0722 * self.values[2 * SIZE + i] as f64
+    }
+
+    pub fn lobe_exponent(&self) -> f64 {
+        self.lobe_exponent
+    }
+
+    // The BRDF for unit directions towards the light and the viewer, given
+    // in a frame where the normal is +z.
+    pub fn eval(&self, wi: &Point3D, wo: &Point3D) -> [f64; 3] {
+        let half = (*wi + *wo).unit_vector();
+        let theta_h = half.z().clamp(-1.0, 1.0).acos();
+        let phi_h = half.y().atan2(half.x());
+
+        // Rotate wi so that the half vector becomes the normal.
+        let (sin_p, cos_p) = (-phi_h).sin_cos();
+        let rotated = Point3D::new(
+            wi.x() * cos_p - wi.y() * sin_p,
+            wi.x() * sin_p + wi.y() * cos_p,
+            wi.z(),
+        );
+        let (sin_t, cos_t) = (-theta_h).sin_cos();
+        let diff = Point3D::new(
+            rotated.x() * cos_t + rotated.z() * sin_t,
+            rotated.y(),
+            -rotated.x() * sin_t + rotated.z() * cos_t,
+        );
+        let theta_d = diff.z().clamp(-1.0, 1.0).acos();
+        let phi_d = diff.y().atan2(diff.x());
+
+        let i = index(
+            theta_h_index(theta_h),
+            theta_d_index(theta_d),
+            phi_d_index(phi_d),
+        );
+        [
+            self.values[i] as f64,
+            self.values[SIZE + i] as f64,
+            self.values[2 * SIZE + i] as f64,
+        ]
+    }
+}
+
+// The BRDF in the MERL .binary format.
+#[cfg(test)]
+impl MerlBrdf {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for dim in [90_i32, 90, 180] {
+            bytes.extend_from_slice(&dim.to_le_bytes());
+        }
+        for (c, scale) in SCALE.iter().enumerate() {
+            for i in 0..SIZE {
+                bytes.extend_from_slice(&(self.values[c * SIZE + i] as f64 / scale).to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+#[test]
+fn test_merl_lookup() {
+    // Red everywhere, plus green where the half vector is near the normal.
+    let brdf = MerlBrdf::from_fn(|theta_h, _, _| {
+        let green = if theta_h < 5.0_f64.to_radians() {
+            10.0
+        } else {
+            0.0
+        };
+        [0.25, green, 0.0]
+    });
+    let wo = Point3D::new(1.0, 0.0, 1.0).unit_vector();
+    let mirror = Point3D::new(-1.0, 0.0, 1.0).unit_vector();
+    let off = Point3D::new(0.0, 1.0, 1.0).unit_vector();
+    assert_eq!(brdf.eval(&mirror, &wo), [0.25, 10.0, 0.0]);
+    assert_eq!(brdf.eval(&off, &wo), [0.25, 0.0, 0.0]);
+    assert!(brdf.lobe_exponent() > 50.0);
+
+    // Round trip through the file format.
+    let bytes = brdf.to_bytes();
+    let loaded = MerlBrdf::from_bytes(&bytes).unwrap();
+    let [r, g, _] = loaded.eval(&mirror, &wo);
+    assert_approx_eq!(r, 0.25, 1e-6);
+    assert_approx_eq!(g, 10.0, 1e-5);
+    assert!(MerlBrdf::from_bytes(&bytes[..100]).is_err());
+}
+
+#[test]
+fn test_merl_cache() {
+    let path = temp_path("cached.binary");
+    fs::write(&path, MerlBrdf::from_fn(|_, _, _| [0.25; 3]).to_bytes()).unwrap();
+    let a = MerlBrdf::load(&path);
+    let b = MerlBrdf::load(&path);
+    assert!(Arc::ptr_eq(&a, &b));
+    fs::remove_file(&path).unwrap();
+}
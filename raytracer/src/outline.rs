@@ -0,0 +1,172 @@
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+
+use crate::materials::SrgbAsArray;
+use crate::point3d::Point3D;
+
+// What the camera sees through the centre of a pixel.
+#[derive(Debug, Clone, Copy)]
+pub struct GBufferSample {
+    pub depth: f64,
+    pub normal: Point3D,
+    // Index of the object in the scene.
+    pub object: usize,
+}
+
+// The G-buffer holds one sample per pixel, or None where the camera sees
+// the sky.
+pub type GBuffer = Vec<Option<GBufferSample>>;
+
+fn black() -> Srgb {
+    Srgb::new(0.0, 0.0, 0.0)
+}
+
+fn one() -> usize {
+    1
+}
+
+fn depth_threshold() -> f64 {
+    0.1
+}
+
+fn crease_angle() -> f64 {
+    60.0
+}
+
+// Draws lines along silhouettes, where the depth jumps between
+// neighbouring pixels or a different object is in view, and creases, where
+// the surface normal turns sharply.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outline {
+    #[serde_as(as = "SrgbAsArray")]
+    #[serde(default = "black")]
+    pub color: Srgb,
+    // Line thickness in pixels.
+    #[serde(default = "one")]
+    pub width: usize,
+    // Change in depth, relative to the nearer pixel, that makes a silhouette.
+    #[serde(default = "depth_threshold")]
+    pub depth_threshold: f64,
+    // Angle in degrees between neighbouring normals that makes a crease.
+    #[serde(default = "crease_angle")]
+    pub crease_angle: f64,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outline {
+    pub fn new() -> Outline {
+        Outline {
+            color: black(),
+            width: one(),
+            depth_threshold: depth_threshold(),
+            crease_angle: crease_angle(),
+        }
+    }
+
+    // Whether pixel a should be drawn as an edge against its neighbour b.
+    // Silhouettes are only drawn on the nearer side, so lines keep their
+    // width.
+    fn is_edge(&self, a: &Option<GBufferSample>, b: &Option<GBufferSample>) -> bool {
+        match (a, b) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(a), Some(b)) => {
+                let nearer = a.depth <= b.depth;
+                if a.object != b.object {
+                    return nearer;
+                }
+                if (b.depth - a.depth).abs() > self.depth_threshold * a.depth.min(b.depth) {
+                    return nearer;
+                }
+                a.normal.dot(&b.normal) < self.crease_angle.to_radians().cos()
+            }
+        }
+    }
+
+    // Paints the outlines over an image in linear color.
+    pub fn draw(
+        &self,
+        pixels: &mut [[f64; 3]],
+        gbuffer: &[Option<GBufferSample>],
+        bounds: (usize, usize),
+    ) {
+        let (width, height) = bounds;
+        let radius = self.width.max(1) as isize;
+        // The outline color is given as it is displayed, with gamma 2.
+        let color = [self.color.red, self.color.green, self.color.blue].map(|c| (c as f64).powi(2));
+        for y in 0..height {
+            for x in 0..width {
+                let here = &gbuffer[y * width + x];
+                let mut edge = false;
+                'search: for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let (nx, ny) = (x as isize + dx, y as isize + dy);
+                        if (dx == 0 && dy == 0)
+                            || dx * dx + dy * dy > radius * radius
+                            || nx < 0
+                            || ny < 0
+                            || nx >= width as isize
+                            || ny >= height as isize
+                        {
+                            continue;
+                        }
+                        if self.is_edge(here, &gbuffer[ny as usize * width + nx as usize]) {
+                            edge = true;
+                            break 'search;
+                        }
+                    }
+                }
+                if edge {
+                    pixels[y * width + x] = color;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_outline_edges() {
+    let outline = Outline::new();
+    let sample = |depth, normal, object| {
+        Some(GBufferSample {
+            depth,
+            normal,
+            object,
+        })
+    };
+    let up = Point3D::new(0.0, 1.0, 0.0);
+    let side = Point3D::new(1.0, 0.0, 0.0);
+
+    // Against the sky, only the object side is an edge.
+    assert!(outline.is_edge(&sample(1.0, up, 0), &None));
+    assert!(!outline.is_edge(&None, &sample(1.0, up, 0)));
+    // Only the nearer of two objects is an edge.
+    assert!(outline.is_edge(&sample(1.0, up, 0), &sample(1.0, up, 1)));
+    assert!(!outline.is_edge(&sample(2.0, up, 1), &sample(1.0, up, 0)));
+    // Smooth surfaces have no edges, creases do.
+    assert!(!outline.is_edge(&sample(1.0, up, 0), &sample(1.01, up, 0)));
+    assert!(outline.is_edge(&sample(1.0, up, 0), &sample(2.0, up, 0)));
+    assert!(outline.is_edge(&sample(1.0, up, 0), &sample(1.0, side, 0)));
+
+    // A single object pixel in the middle of a 3x3 image is outlined, the
+    // sky around it isn't.
+    let mut gbuffer = vec![None; 9];
+    gbuffer[4] = sample(1.0, up, 0);
+    let mut pixels = vec![[1.0; 3]; 9];
+    outline.draw(&mut pixels, &gbuffer, (3, 3));
+    assert_eq!(pixels[4], [0.0; 3]);
+    assert_eq!(pixels.iter().filter(|&&p| p == [0.0; 3]).count(), 1);
+}
+
+#[test]
+fn test_outline_from_json() {
+    let outline = serde_json::from_str::<Outline>(r#"{"width":2}"#).unwrap();
+    assert_eq!(outline.width, 2);
+    assert_eq!(outline.color, black());
+}
@@ -0,0 +1,231 @@
+use exr::prelude::f16;
+use exr::prelude::AnyChannel;
+use exr::prelude::AnyChannels;
+use exr::prelude::Encoding;
+use exr::prelude::FlatSamples;
+use exr::prelude::Image;
+use exr::prelude::Layer;
+use exr::prelude::LayerAttributes;
+use exr::prelude::SmallVec;
+use exr::prelude::Vec2;
+use exr::prelude::WritableImage;
+use image::codecs::hdr::HdrEncoder;
+use image::codecs::png::PngEncoder;
+use image::ExtendedColorType;
+use image::ImageEncoder;
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+#[cfg(test)]
+use exr::prelude::ReadChannels;
+#[cfg(test)]
+use exr::prelude::ReadLayers;
+
+#[cfg(test)]
+use crate::temp_path;
+
+// The kinds of image file a render can be written to, chosen by the
+// extension of the file name. Anything but .exr and .hdr is written as PNG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    // 8 bits per channel, with gamma 2.
+    Png,
+    // Linear color as 16 or 32 bit floats, with the AOVs as extra layers.
+    OpenExr,
+    // Linear color with a shared exponent. It cannot hold negative values.
+    RadianceHdr,
+}
+
+impl ImageFormat {
+    pub fn from_filename(filename: &str) -> ImageFormat {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("exr") => ImageFormat::OpenExr,
+            Some("hdr") => ImageFormat::RadianceHdr,
+            _ => ImageFormat::Png,
+        }
+    }
+}
+
+// How many bits the colors of OpenEXR files are written with. Half floats
+// make files half the size and keep 3 significant digits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExrPrecision {
+    #[default]
+    Half,
+    Float,
+}
+
+// How the values of a layer are stored in an OpenEXR file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExrSamples {
+    Half,
+    Float,
+    // Whole numbers, such as IDs.
+    Integer,
+}
+
+impl From<ExrPrecision> for ExrSamples {
+    fn from(precision: ExrPrecision) -> Self {
+        match precision {
+            ExrPrecision::Half => ExrSamples::Half,
+            ExrPrecision::Float => ExrSamples::Float,
+        }
+    }
+}
+
+// A group of channels in an OpenEXR file. The channels are named after the
+// layer, like depth.Z or normal.X, except for the image's own R, G and B.
+pub struct ExrLayer {
+    pub name: &'static str,
+    // The names of the first one, two or three values of each pixel.
+    pub channels: &'static [&'static str],
+    pub values: Vec<[f64; 3]>,
+    pub samples: ExrSamples,
+}
+
+impl ExrLayer {
+    // The image itself, in linear color.
+    pub fn image(colors: Vec<[f64; 3]>, precision: ExrPrecision) -> ExrLayer {
+        ExrLayer {
+            name: "",
+            channels: &["R", "G", "B"],
+            values: colors,
+            samples: precision.into(),
+        }
+    }
+
+    fn any_channels(&self) -> impl Iterator<Item = AnyChannel<FlatSamples>> + '_ {
+        self.channels.iter().enumerate().map(|(c, channel)| {
+            let name = if self.name.is_empty() {
+                channel.to_string()
+            } else {
+                format!("{}.{}", self.name, channel)
+            };
+            let values = self.values.iter().map(move |v| v[c]);
+            let samples = match self.samples {
+                ExrSamples::Half => FlatSamples::F16(values.map(f16::from_f64).collect()),
+                ExrSamples::Float => FlatSamples::F32(values.map(|v| v as f32).collect()),
+                ExrSamples::Integer => FlatSamples::U32(values.map(|v| v as u32).collect()),
+            };
+            AnyChannel::new(name.as_str(), samples)
+        })
+    }
+}
+
+pub fn write_png(
+    filename: &str,
+    pixels: &[u8],
+    bounds: (usize, usize),
+) -> Result<(), image::ImageError> {
+    let output = File::create(filename)?;
+    let encoder = PngEncoder::new(output);
+    encoder.write_image(
+        pixels,
+        bounds.0 as u32,
+        bounds.1 as u32,
+        ExtendedColorType::Rgb8,
+    )
+}
+
+// Writes linear colors to a Radiance .hdr file. Negative values, which the
+// format cannot hold, are written as 0.
+pub fn write_hdr(
+    filename: &str,
+    colors: &[[f64; 3]],
+    bounds: (usize, usize),
+) -> Result<(), image::ImageError> {
+    let output = BufWriter::new(File::create(filename)?);
+    let pixels: Vec<Rgb<f32>> = colors
+        .iter()
+        .map(|color| Rgb(color.map(|c| c.max(0.0) as f32)))
+        .collect();
+    HdrEncoder::new(output).encode(&pixels, bounds.0, bounds.1)
+}
+
+// Writes the layers to a single part OpenEXR file, which compositing tools
+// show as one layer per channel name prefix.
+pub fn write_exr(
+    filename: &str,
+    layers: &[ExrLayer],
+    bounds: (usize, usize),
+) -> Result<(), exr::error::Error> {
+    let channels: SmallVec<[AnyChannel<FlatSamples>; 4]> =
+        layers.iter().flat_map(|l| l.any_channels()).collect();
+    let layer = Layer::new(
+        Vec2(bounds.0, bounds.1),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer).write().to_file(filename)
+}
+
+#[test]
+fn test_image_format() {
+    assert_eq!(ImageFormat::from_filename("out.png"), ImageFormat::Png);
+    assert_eq!(ImageFormat::from_filename("out"), ImageFormat::Png);
+    assert_eq!(
+        ImageFormat::from_filename("a.b/OUT.EXR"),
+        ImageFormat::OpenExr
+    );
+    assert_eq!(
+        ImageFormat::from_filename("out.hdr"),
+        ImageFormat::RadianceHdr
+    );
+}
+
+#[test]
+fn test_write_hdr() {
+    let colors = [[4.0, 0.5, 0.0], [0.25, 1.0, -1.0]];
+    let path = temp_path("test_write.hdr");
+    write_hdr(&path, &colors, (2, 1)).unwrap();
+    let image = image::open(&path).unwrap().into_rgb32f();
+    // Values brighter than white survive.
+    assert_eq!(image.get_pixel(0, 0).0, [4.0, 0.5, 0.0]);
+    assert_eq!(image.get_pixel(1, 0).0, [0.25, 1.0, 0.0]);
+}
+
+#[test]
+fn test_write_exr() {
+    let layers = [
+        ExrLayer::image(vec![[4.0, 0.5, 0.0], [0.25, 1.0, -1.0]], ExrPrecision::Half),
+        ExrLayer {
+            name: "depth",
+            channels: &["Z"],
+            values: vec![[1000.5; 3], [0.0; 3]],
+            samples: ExrSamples::Float,
+        },
+        ExrLayer {
+            name: "object_id",
+            channels: &["id"],
+            values: vec![[7.0; 3], [0.0; 3]],
+            samples: ExrSamples::Integer,
+        },
+    ];
+    let path = temp_path("test_write.exr");
+    write_exr(&path, &layers, (2, 1)).unwrap();
+    let image = exr::prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .first_valid_layer()
+        .all_attributes()
+        .from_file(&path)
+        .unwrap();
+    let channels = &image.layer_data.channel_data.list;
+    let names: Vec<String> = channels.iter().map(|c| c.name.to_string()).collect();
+    assert_eq!(names, ["B", "G", "R", "depth.Z", "object_id.id"]);
+    let values = |c: usize| -> Vec<f32> { channels[c].sample_data.values_as_f32().collect() };
+    assert_eq!(values(2), [4.0, 0.25]);
+    assert_eq!(values(0), [0.0, -1.0]);
+    assert_eq!(values(3), [1000.5, 0.0]);
+    assert!(matches!(channels[4].sample_data, FlatSamples::U32(_)));
+    assert_eq!(values(4), [7.0, 0.0]);
+}
@@ -19,8 +19,7 @@ impl Point3D {
         Point3D { x, y, z }
     }
 
-    pub fn random(min: f64, max: f64) -> Point3D {
-        let mut rng = rand::thread_rng();
+    pub fn random(min: f64, max: f64, rng: &mut impl Rng) -> Point3D {
         Point3D::new(
             rng.gen_range(min..max),
             rng.gen_range(min..max),
@@ -28,13 +27,15 @@ impl Point3D {
         )
     }
 
-    pub fn random_in_unit_sphere() -> Point3D {
-        loop {
-            let p = Point3D::random(-1.0, 1.0);
-            if p.length_squared() < 1.0 {
-                return p;
-            }
-        }
+    // Uniformly distributed in the unit ball. This always uses three random
+    // numbers, rather than rejecting points outside the ball, so that samplers
+    // which spread out each dimension stay lined up between samples.
+    pub fn random_in_unit_sphere(rng: &mut impl Rng) -> Point3D {
+        let z = 1.0 - 2.0 * rng.gen::<f64>();
+        let phi = 2.0 * f64::consts::PI * rng.gen::<f64>();
+        let radius = rng.gen::<f64>().cbrt();
+        let ring = (1.0 - z * z).max(0.0).sqrt();
+        Point3D::new(ring * phi.cos(), ring * phi.sin(), z) * radius
     }
 
     pub fn x(&self) -> f64 {
@@ -257,10 +258,12 @@ fn test_length_squared() {
 
 #[test]
 fn test_random() {
-    let p = Point3D::random(-1.0, 1.0);
+    let p = Point3D::random(-1.0, 1.0, &mut rand::thread_rng());
     assert!(p.x() >= -1.0 && p.x() <= 1.0);
     assert!(p.y() >= -1.0 && p.y() <= 1.0);
     assert!(p.z() >= -1.0 && p.z() <= 1.0);
+    let p = Point3D::random_in_unit_sphere(&mut rand::thread_rng());
+    assert!(p.length_squared() < 1.0);
 }
 
 #[test]
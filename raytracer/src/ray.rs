@@ -8,11 +8,19 @@ use assert_approx_eq::assert_approx_eq;
 pub struct Ray {
     pub origin: Point3D,
     pub direction: Point3D,
+    // Angle in radians by which the cone of space covered by this ray widens
+    // per unit distance, used to pick texture detail. Zero for secondary
+    // rays, which are treated as infinitely thin.
+    pub spread: f64,
 }
 
 impl Ray {
     pub fn new(origin: Point3D, direction: Point3D) -> Ray {
-        Ray { origin, direction }
+        Ray {
+            origin,
+            direction,
+            spread: 0.0,
+        }
     }
 
     pub fn at(&self, t: f64) -> Point3D {
@@ -24,14 +32,24 @@ pub struct HitRecord<'material> {
     pub t: f64,
     pub point: Point3D,
     pub normal: Point3D,
+    // Unit vectors along the surface in the directions of increasing u and
+    // increasing v, forming a tangent frame with the normal.
+    pub tangent: Point3D,
+    pub bitangent: Point3D,
     pub front_face: bool,
     pub material: &'material Material,
     pub u: f64,
     pub v: f64,
+    // Approximate width of the ray's footprint in (u, v) texture space.
+    pub footprint: f64,
+    // Set for surfaces that are a thin sheet rather than the boundary of a
+    // solid, so light passing through does not enter a volume. Only
+    // materials that let light through, Glass and Translucent, use it.
+    pub thin: bool,
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
 }
 
 #[test]
@@ -1,71 +1,115 @@
-use image::png::PNGEncoder;
-use image::ColorType;
-use palette::Pixel;
 use palette::Srgb;
 use rand::Rng;
 use rayon::prelude::*;
-use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
 use std::time::Instant;
 
+use crate::aov::material_ids;
+use crate::aov::Aov;
+use crate::aov::Radiance;
+use crate::checkpoint;
 use crate::config::Config;
+use crate::denoise::Features;
+use crate::film::colors_to_rgb8;
+use crate::film::Film;
+use crate::film::FilmPixel;
+use crate::film::LineSplats;
 use crate::materials::Material;
 use crate::materials::Scatterable;
+use crate::outline::GBuffer;
+use crate::outline::GBufferSample;
+use crate::output::write_exr;
+use crate::output::write_hdr;
+use crate::output::write_png;
+use crate::output::ExrLayer;
+use crate::output::ExrSamples;
+use crate::output::ImageFormat;
 use crate::ray::HitRecord;
 use crate::ray::Hittable;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::sphere::Sphere;
+use crate::textures::luminance;
+use crate::textures::WrapMode;
 
+#[cfg(test)]
+use exr::prelude::ReadChannels;
+#[cfg(test)]
+use exr::prelude::ReadLayers;
 #[cfg(test)]
 use std::fs;
 
 #[cfg(test)]
 use crate::point3d::Point3D;
 
+#[cfg(test)]
+use crate::adaptive::AdaptiveSampling;
 #[cfg(test)]
 use crate::camera::Camera;
 #[cfg(test)]
+use crate::config::Progressive;
+#[cfg(test)]
 use crate::config::Sky;
 #[cfg(test)]
+use crate::denoise::Denoise;
+#[cfg(test)]
+use crate::filter::PixelFilter;
+#[cfg(test)]
 use crate::materials::Lambertian;
 #[cfg(test)]
 use crate::materials::Light;
+#[cfg(test)]
+use crate::output::ExrPrecision;
+#[cfg(test)]
+use crate::sampler::SamplerKind;
+#[cfg(test)]
+use crate::temp_path;
 
-fn write_image(
-    filename: &str,
-    pixels: &[u8],
-    bounds: (usize, usize),
-) -> Result<(), std::io::Error> {
-    let output = File::create(filename)?;
-    let encoder = PNGEncoder::new(output);
-    encoder.encode(pixels, bounds.0 as u32, bounds.1 as u32, ColorType::RGB(8))?;
-    Ok(())
-}
-
+// The closest hit along a ray, and the index of the object hit.
 fn hit_world<'material>(
-    world: &'material Vec<Sphere>,
+    world: &'material [Sphere],
     r: &Ray,
     t_min: f64,
     t_max: f64,
-) -> Option<HitRecord<'material>> {
+) -> Option<(usize, HitRecord<'material>)> {
     let mut closest_so_far = t_max;
     let mut hit_record = None;
-    for sphere in world {
+    for (i, sphere) in world.iter().enumerate() {
         if let Some(hit) = sphere.hit(r, t_min, closest_so_far) {
             closest_so_far = hit.t;
-            hit_record = Some(hit);
+            hit_record = Some((i, hit));
         }
     }
     hit_record
 }
 
 fn clamp(value: f32) -> f32 {
-    if value < 0.0 {
-        0.0
-    } else if value > 1.0 {
-        1.0
-    } else {
-        value
+    value.clamp(0.0, 1.0)
+}
+
+// Sum of the diffuse lighting from every light visible from the hit, used
+// to pick a toon shading band. Scenes without lights are lit from above.
+fn toon_diffuse(hit_record: &HitRecord, scene: &Config, lights: &[Sphere]) -> f64 {
+    if lights.is_empty() {
+        return hit_record.normal.y();
     }
+    lights
+        .iter()
+        .map(|light| {
+            let to_light = light.center - hit_record.point;
+            let cos = hit_record.normal.dot(&to_light.unit_vector());
+            if cos <= 0.0 {
+                return 0.0;
+            }
+            let shadow_ray = Ray::new(hit_record.point, to_light);
+            match hit_world(&scene.objects, &shadow_ray, 0.001, 1.0) {
+                Some((_, hit)) if !matches!(hit.material, Material::Light(_)) => 0.0,
+                _ => cos,
+            }
+        })
+        .sum()
 }
 
 fn ray_color(
@@ -74,36 +118,74 @@ fn ray_color(
     lights: &Vec<Sphere>,
     max_depth: usize,
     depth: usize,
+    rng: &mut Sampler,
 ) -> Srgb {
-    let mut rng = rand::thread_rng();
+    ray_radiance(ray, scene, lights, max_depth, depth, rng).total()
+}
+
+// The light carried back along a ray, split into emission, direct and
+// indirect light for the AOVs.
+fn ray_radiance(
+    ray: &Ray,
+    scene: &Config,
+    lights: &Vec<Sphere>,
+    max_depth: usize,
+    depth: usize,
+    rng: &mut Sampler,
+) -> Radiance {
+    if depth == 0 {
+        return Radiance::default();
+    }
+    let hit = hit_world(&scene.objects, ray, 0.001, f64::MAX);
+    hit_radiance(
+        ray,
+        hit.map(|(_, hit_record)| hit_record),
+        scene,
+        lights,
+        max_depth,
+        depth,
+        rng,
+    )
+}
 
-    if depth <= 0 {
-        return Srgb::new(0.0, 0.0, 0.0);
+// The light carried back along a ray that has been traced to hit, or to the
+// sky if it hit nothing.
+fn hit_radiance(
+    ray: &Ray,
+    hit: Option<HitRecord>,
+    scene: &Config,
+    lights: &Vec<Sphere>,
+    max_depth: usize,
+    depth: usize,
+    rng: &mut Sampler,
+) -> Radiance {
+    if depth == 0 {
+        return Radiance::default();
     }
-    let hit = hit_world(&scene.objects, ray, 0.001, std::f64::MAX);
     match hit {
         Some(hit_record) => {
-            let scattered = hit_record.material.scatter(ray, &hit_record);
+            if let Material::Toon(toon) = hit_record.material {
+                let diffuse = toon_diffuse(&hit_record, scene, lights);
+                return Radiance::lit(toon.shade(&hit_record, diffuse));
+            }
+            let scattered = hit_record.material.scatter(ray, &hit_record, rng);
             match scattered {
                 Some((scattered_ray, albedo)) => {
                     let mut light_red = 0.0;
                     let mut light_green = 0.0;
                     let mut light_blue = 0.0;
                     let mut prob = 0.1;
-                    match hit_record.material {
-                        Material::Glass(_) => {
-                            prob = 0.05;
-                        }
-                        _ => {}
+                    if let Material::Glass(_) = hit_record.material {
+                        prob = 0.05;
                     }
-                    if lights.len() > 0
+                    if !lights.is_empty()
                         && rng.gen::<f64>() > (1.0 - lights.len() as f64 * prob)
                         && depth > (max_depth - 2)
                     {
                         for light in lights {
                             let light_ray =
                                 Ray::new(hit_record.point, light.center - hit_record.point);
-                            let target_color = ray_color(&light_ray, scene, lights, 2, 1);
+                            let target_color = ray_color(&light_ray, scene, lights, 2, 1, rng);
                             light_red += albedo.red * target_color.red;
                             light_green += albedo.green * target_color.green;
                             light_blue += albedo.blue * target_color.blue;
@@ -114,52 +196,45 @@ fn ray_color(
                     }
                     match scattered_ray {
                         Some(sr) => {
-                            let target_color = ray_color(&sr, scene, lights, max_depth, depth - 1);
-                            return Srgb::new(
-                                clamp(light_red + albedo.red * target_color.red),
-                                clamp(light_green + albedo.green * target_color.green),
-                                clamp(light_blue + albedo.blue * target_color.blue),
-                            );
+                            let incoming =
+                                ray_radiance(&sr, scene, lights, max_depth, depth - 1, rng);
+                            let light = Srgb::new(light_red, light_green, light_blue);
+                            Radiance::scattered(light, albedo, &incoming)
                         }
-                        None => albedo,
+                        None => Radiance::emitted(albedo),
                     }
                 }
                 None => {
                     // don't bother bouncing absorbed rays towards lights
                     // (they would be absorbed in the opposite direction).
-                    return Srgb::new(0.0, 0.0, 0.0);
+                    Radiance::default()
                 }
             }
         }
         None => {
             let t: f32 = clamp(0.5 * (ray.direction.unit_vector().y() as f32 + 1.0));
             let u: f32 = clamp(0.5 * (ray.direction.unit_vector().x() as f32 + 1.0));
-            match &scene.sky {
-                None => {
-                    return Srgb::new(0.0, 0.0, 0.0);
-                }
+            let sky = match &scene.sky {
+                None => Srgb::new(0.0, 0.0, 0.0),
                 Some(sky) => match &sky.texture {
-                    None => {
-                        return Srgb::new(
-                            (1.0 - t) * 1.0 + t * 0.5,
-                            (1.0 - t) * 1.0 + t * 0.7,
-                            (1.0 - t) * 1.0 + t * 1.0,
-                        );
-                    }
-                    Some((pixels, width, height, _)) => {
-                        let x = (u * (*width - 1) as f32) as usize;
-                        let y = ((1.0 - t) * (*height - 1) as f32) as usize;
-                        let pixel_red = &pixels[(y * *width + x) * 3];
-                        let pixel_green = &pixels[(y * *width + x) * 3 + 1];
-                        let pixel_blue = &pixels[(y * *width + x) * 3 + 2];
-                        return Srgb::new(
-                            0.7 * *pixel_red as f32 / 255.0,
-                            0.7 * *pixel_green as f32 / 255.0,
-                            0.7 * *pixel_blue as f32 / 255.0,
-                        );
+                    None => Srgb::new(
+                        (1.0 - t) * 1.0 + t * 0.5,
+                        (1.0 - t) * 1.0 + t * 0.7,
+                        (1.0 - t) * 1.0 + t * 1.0,
+                    ),
+                    Some((pixels, _, _, _)) => {
+                        // u spans the sky over half a turn of the view.
+                        let footprint = 0.5 * ray.spread;
+                        // Clamped vertically, so the top and bottom rows
+                        // are not blended together.
+                        let wrap = [sky.wrap, WrapMode::Clamp];
+                        let [r, g, b, _] =
+                            pixels.sample_rgba(u as f64, t as f64, footprint, sky.filter, wrap);
+                        Srgb::new(0.7 * r, 0.7 * g, 0.7 * b)
                     }
                 },
-            }
+            };
+            Radiance::emitted(sky)
         }
     }
 }
@@ -183,47 +258,182 @@ fn test_ray_color() {
             1.333,
         ),
         objects: Vec::new(),
+        outline: None,
+        seed: 0,
+        sampler: SamplerKind::Independent,
+        filter: PixelFilter::default(),
+        denoise: None,
+        aovs: vec![],
+        exr_precision: ExrPrecision::Half,
+        adaptive: None,
+        progressive: None,
     };
     let l = Vec::new();
-    assert_eq!(ray_color(&r, &scene, &l, 2, 2), Srgb::new(0.75, 0.85, 1.0));
+    let mut rng = Sampler::independent(0);
+    assert_eq!(
+        ray_color(&r, &scene, &l, 2, 2, &mut rng),
+        Srgb::new(0.75, 0.85, 1.0)
+    );
 }
 
-fn render_line(pixels: &mut [u8], scene: &Config, lights: &Vec<Sphere>, y: usize) {
-    let mut rng = rand::thread_rng();
+#[test]
+fn test_light_emission() {
+    // Light brighter than white lights the surfaces it falls on more.
+    let lit_ball = |emission: f64| {
+        let mut light = Light::new();
+        light.emission = emission.into();
+        let objects = vec![
+            Sphere::new(
+                Point3D::new(0.0, 0.0, 0.0),
+                1.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+            Sphere::new(Point3D::new(0.0, 3.0, -1.0), 0.5, Material::Light(light)),
+        ];
+        let scene = Config {
+            width: 80,
+            height: 60,
+            samples_per_pixel: 1,
+            max_depth: 4,
+            sky: None,
+            camera: Camera::new(
+                Point3D::new(0.0, 0.0, -3.0),
+                Point3D::new(0.0, 0.0, 0.0),
+                Point3D::new(0.0, 1.0, 0.0),
+                20.0,
+                1.333,
+            ),
+            objects,
+            outline: None,
+            seed: 0,
+            sampler: SamplerKind::Independent,
+            filter: PixelFilter::default(),
+            denoise: None,
+            aovs: vec![],
+            exr_precision: ExrPrecision::Half,
+            adaptive: None,
+            progressive: None,
+        };
+        let lights = find_lights(&scene.objects);
+        let r = Ray::new(Point3D::new(0.0, 0.5, -3.0), Point3D::new(0.0, 0.0, 1.0));
+        let mut rng = Sampler::independent(0);
+        (0..1000)
+            .map(|_| ray_color(&r, &scene, &lights, 4, 4, &mut rng).red as f64)
+            .sum::<f64>()
+    };
+    let (dim, bright) = (lit_ball(1.0), lit_ball(4.0));
+    assert!(dim > 0.0);
+    assert!((bright / dim - 4.0).abs() < 1e-3);
+}
 
+// Takes up to pass_samples more samples for each pixel of a line that has
+// not yet finished, and returns them weighted by the pixel filter.
+fn render_line(
+    line: &mut [FilmPixel],
+    scene: &Config,
+    lights: &Vec<Sphere>,
+    material_ids: &[[u32; 2]],
+    y: usize,
+    pass_samples: u32,
+    max_samples: u32,
+) -> LineSplats {
     let bounds = (scene.width, scene.height);
+    let spread = scene.camera.pixel_spread(bounds.1);
+    let aovs = !scene.aovs.is_empty();
+    let mut splats = LineSplats::new(y, scene.filter.reach(), bounds.0, bounds.1, aovs);
 
-    for x in 0..bounds.0 {
-        let mut pixel_colors: Vec<f32> = vec![0.0; 3];
-        for _s in 0..scene.samples_per_pixel {
-            let u = (x as f64 + rng.gen::<f64>()) / (bounds.0 as f64 - 1.0);
-            let v = (bounds.1 as f64 - (y as f64 + rng.gen::<f64>())) / (bounds.1 as f64 - 1.0);
-            let r = scene.camera.get_ray(u, v);
-            let c = ray_color(&r, scene, lights, scene.max_depth, scene.max_depth);
-            pixel_colors[0] += c.red;
-            pixel_colors[1] += c.green;
-            pixel_colors[2] += c.blue;
+    for (x, pixel) in line.iter_mut().enumerate() {
+        for _s in 0..pass_samples {
+            if is_finished(pixel, scene, max_samples) {
+                break;
+            }
+            let mut rng = Sampler::new(
+                scene.sampler,
+                scene.seed,
+                (x, y),
+                bounds.0,
+                pixel.stats.count,
+                scene.samples_per_pixel,
+            );
+            let (dx, dy) = rng.next_2d();
+            let u = (x as f64 + dx) / (bounds.0 as f64 - 1.0);
+            let v = (bounds.1 as f64 - (y as f64 + dy)) / (bounds.1 as f64 - 1.0);
+            let mut r = scene.camera.get_ray(u, v);
+            r.spread = spread;
+            let hit = hit_world(&scene.objects, &r, 0.001, f64::MAX);
+            if scene.denoise.is_some() || aovs {
+                pixel.features.add(&hit_features(&r, hit.as_ref()));
+                if let Some((i, hit_record)) = &hit {
+                    pixel.aovs.hits += 1;
+                    pixel.aovs.uv[0] += hit_record.u;
+                    pixel.aovs.uv[1] += hit_record.v;
+                    if pixel.stats.count == 0 {
+                        let side = if hit_record.front_face { 0 } else { 1 };
+                        pixel.aovs.object = *i as u32 + 1;
+                        pixel.aovs.material = material_ids[*i][side];
+                    }
+                }
+            }
+            let radiance = hit_radiance(
+                &r,
+                hit.map(|(_, hit_record)| hit_record),
+                scene,
+                lights,
+                scene.max_depth,
+                scene.max_depth,
+                &mut rng,
+            );
+            pixel.stats.add(luminance(radiance.total()));
+            splats.add(&scene.filter, x as f64 + dx, y as f64 + dy, &radiance);
         }
-        let scale = 1.0 / scene.samples_per_pixel as f32;
-        let color = Srgb::new(
-            (scale * pixel_colors[0]).sqrt(),
-            (scale * pixel_colors[1]).sqrt(),
-            (scale * pixel_colors[2]).sqrt(),
-        );
-        let pixel: [u8; 3] = color.into_format().into_raw();
-        pixels[x * 3] = pixel[0];
-        pixels[x * 3 + 1] = pixel[1];
-        pixels[x * 3 + 2] = pixel[2];
+    }
+    splats
+}
+
+// What the denoiser is guided by, where a camera ray first hits the scene.
+fn hit_features(ray: &Ray, hit: Option<&(usize, HitRecord)>) -> Features {
+    match hit {
+        Some((_, hit_record)) => {
+            let albedo = hit_record.material.albedo(hit_record);
+            let normal = hit_record.normal;
+            Features {
+                albedo: [albedo.red, albedo.green, albedo.blue].map(|c| c as f64),
+                normal: [normal.x(), normal.y(), normal.z()],
+                depth: hit_record.t * ray.direction.length(),
+            }
+        }
+        None => Features::default(),
     }
 }
 
-fn find_lights(world: &Vec<Sphere>) -> Vec<Sphere> {
+fn is_finished(pixel: &FilmPixel, scene: &Config, max_samples: u32) -> bool {
+    pixel.stats.count >= max_samples
+        || scene
+            .adaptive
+            .as_ref()
+            .is_some_and(|adaptive| adaptive.converged(&pixel.stats))
+}
+
+// Records what the camera sees through the centre of each pixel of a line.
+fn gbuffer_line(gbuffer: &mut [Option<GBufferSample>], scene: &Config, y: usize) {
+    let bounds = (scene.width, scene.height);
+    for (x, sample) in gbuffer.iter_mut().enumerate() {
+        let u = (x as f64 + 0.5) / (bounds.0 as f64 - 1.0);
+        let v = (bounds.1 as f64 - (y as f64 + 0.5)) / (bounds.1 as f64 - 1.0);
+        let r = scene.camera.get_ray(u, v);
+        *sample =
+            hit_world(&scene.objects, &r, 0.001, f64::MAX).map(|(object, hit)| GBufferSample {
+                depth: hit.t * r.direction.length(),
+                normal: hit.normal,
+                object,
+            });
+    }
+}
+
+fn find_lights(world: &[Sphere]) -> Vec<Sphere> {
     world
         .iter()
-        .filter(|s| match s.material {
-            Material::Light(_) => true,
-            _ => false,
-        })
+        .filter(|s| matches!(s.material, Material::Light(_)))
         .cloned()
         .collect()
 }
@@ -239,30 +449,319 @@ fn test_find_lights() {
         Sphere::new(
             Point3D::new(0.0, 0.0, -1.0),
             0.5,
-            Material::Lambertian(Lambertian::new(Srgb::new(
-                0.5 as f32, 0.5 as f32, 0.5 as f32,
-            ))),
+            Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
         ),
     ];
     assert_eq!(find_lights(&world).len(), 1);
 }
 
+// The file name with a suffix added to its stem, e.g. out.png -> out_a.png.
+fn suffixed_name(filename: &str, suffix: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}_{}.{}", stem, suffix, extension),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// The file name for a numbered snapshot, e.g. out.png -> out_0001.png.
+fn snapshot_name(filename: &str, pass: usize) -> String {
+    suffixed_name(filename, &format!("{:0>4}", pass))
+}
+
+// The file name for an AOV, e.g. out.png -> out_depth.png.
+fn aov_name(filename: &str, aov: &Aov) -> String {
+    suffixed_name(filename, aov.name())
+}
+
+// The finished image in linear color, denoised and outlined as the scene
+// asks.
+fn film_colors(film: &Film, scene: &Config, gbuffer: Option<&GBuffer>) -> Vec<[f64; 3]> {
+    let mut colors = match &scene.denoise {
+        Some(denoise) => denoise.apply(film),
+        None => film.pixels.iter().map(|p| p.color()).collect(),
+    };
+    if let (Some(outline), Some(gbuffer)) = (&scene.outline, gbuffer) {
+        outline.draw(&mut colors, gbuffer, (film.width, film.height));
+    }
+    colors
+}
+
+// Writes the image in the format given by the file name, along with the
+// scene's AOVs when with_aovs is set. OpenEXR files hold the AOVs as extra
+// layers, other formats write each AOV to its own file.
+fn write_film(
+    filename: &str,
+    film: &Film,
+    scene: &Config,
+    gbuffer: Option<&GBuffer>,
+    with_aovs: bool,
+) {
+    let bounds = (film.width, film.height);
+    let colors = film_colors(film, scene, gbuffer);
+    let aovs: &[Aov] = if with_aovs { &scene.aovs } else { &[] };
+    match ImageFormat::from_filename(filename) {
+        ImageFormat::Png => {
+            write_png(filename, &colors_to_rgb8(&colors), bounds).expect("error writing image");
+            for aov in aovs {
+                write_png(&aov_name(filename, aov), &aov.to_rgb8(film), bounds)
+                    .expect("error writing image");
+            }
+        }
+        ImageFormat::RadianceHdr => {
+            write_hdr(filename, &colors, bounds).expect("error writing image");
+            for aov in aovs {
+                write_hdr(&aov_name(filename, aov), &aov.positive_values(film), bounds)
+                    .expect("error writing image");
+            }
+        }
+        ImageFormat::OpenExr => {
+            let mut layers = vec![ExrLayer::image(colors, scene.exr_precision)];
+            layers.extend(
+                aovs.iter()
+                    .map(|aov| aov.exr_layer(film, scene.exr_precision)),
+            );
+            write_exr(filename, &layers, bounds).expect("error writing image");
+        }
+    }
+}
+
+// Settings that are given on the command line rather than in the scene.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    // Stop adding passes before this much time has passed.
+    pub time_limit: Option<Duration>,
+    // Stop adding passes once the average estimated noise per pixel, in
+    // display brightness from 0 to 1, falls below this.
+    pub target_noise: Option<f64>,
+    // The most samples to take for each pixel. A noise target might never
+    // be met, so without a time limit it defaults to 16 times
+    // samples_per_pixel.
+    pub max_samples: Option<u32>,
+    // Save the render in progress to this file every checkpoint_interval.
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: Duration,
+    // Continue from the checkpoint file rather than starting afresh.
+    pub resume: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            time_limit: None,
+            target_noise: None,
+            max_samples: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(60),
+            resume: false,
+        }
+    }
+}
+
+impl RenderOptions {
+    // With a budget, samples_per_pixel no longer limits the render.
+    fn has_budget(&self) -> bool {
+        self.time_limit.is_some() || self.target_noise.is_some()
+    }
+}
+
+// The average over all pixels of the estimated error in their displayed
+// brightness.
+fn estimated_noise(film: &Film) -> f64 {
+    let total: f64 = film
+        .pixels
+        .iter()
+        .map(|p| p.stats.display_error().min(1.0))
+        .sum();
+    total / film.pixels.len() as f64
+}
+
+const BAND_ROWS: usize = 64;
+
+// Adds a pass of up to pass_samples samples to each unfinished pixel.
+fn render_pass(
+    film: &mut Film,
+    scene: &Config,
+    lights: &Vec<Sphere>,
+    material_ids: &[[u32; 2]],
+    pass_samples: u32,
+    max_samples: u32,
+) {
+    let (width, height) = (film.width, film.height);
+    // The rows are rendered in bands, to bound the memory used by their
+    // splats.
+    for band in (0..height).step_by(BAND_ROWS) {
+        let rows = band * width..(band + BAND_ROWS).min(height) * width;
+        let splats: Vec<LineSplats> = film.pixels[rows]
+            .par_chunks_mut(width)
+            .enumerate()
+            .map(|(y, line)| {
+                let y = band + y;
+                render_line(
+                    line,
+                    scene,
+                    lights,
+                    material_ids,
+                    y,
+                    pass_samples,
+                    max_samples,
+                )
+            })
+            .collect();
+        film.add_splats(&splats);
+    }
+}
+
 pub fn render(filename: &str, scene: Config) {
+    render_with_options(filename, scene, &RenderOptions::default());
+}
+
+pub fn render_with_options(filename: &str, scene: Config, options: &RenderOptions) {
     let image_width = scene.width;
     let image_height = scene.height;
 
-    let mut pixels = vec![0; image_width * image_height * 3];
-    let bands: Vec<(usize, &mut [u8])> = pixels.chunks_mut(image_width * 3).enumerate().collect();
-
+    let fingerprint = checkpoint::fingerprint(&serde_json::to_string(&scene).unwrap());
+    let (mut film, mut pass) = match (&options.checkpoint, options.resume) {
+        (Some(path), true) => {
+            match checkpoint::load(path, fingerprint, image_width, image_height) {
+                Ok((film, pass)) => {
+                    println!(
+                        "Resuming after pass {} with {:.1} samples per pixel",
+                        pass,
+                        film.total_samples() as f64 / film.pixels.len() as f64
+                    );
+                    (film, pass)
+                }
+                // Nothing saved yet, so this is the first run.
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    println!("No checkpoint at {}, starting from the beginning", path);
+                    (Film::new(image_width, image_height), 0)
+                }
+                Err(error) => panic!("Unable to resume from checkpoint: {}", error),
+            }
+        }
+        _ => (Film::new(image_width, image_height), 0),
+    };
     let lights = find_lights(&scene.objects);
+    let ids = material_ids(&scene.objects);
 
     let start = Instant::now();
-    bands.into_par_iter().for_each(|(i, band)| {
-        render_line(band, &scene, &lights, i);
+    let gbuffer = scene.outline.as_ref().map(|_| {
+        let mut gbuffer: GBuffer = vec![None; image_width * image_height];
+        gbuffer
+            .par_chunks_mut(image_width)
+            .enumerate()
+            .for_each(|(y, line)| gbuffer_line(line, &scene, y));
+        gbuffer
     });
+
+    // Budgets and checkpoints need the render split into passes.
+    let pass_samples = match &scene.progressive {
+        Some(progressive) => progressive.samples_per_pass.max(1),
+        None if options.has_budget() || options.checkpoint.is_some() => 16,
+        None => scene.samples_per_pixel,
+    };
+    let max_samples = match options.max_samples {
+        Some(max_samples) => max_samples,
+        None if options.time_limit.is_some() => u32::MAX,
+        None if options.target_noise.is_some() => scene.samples_per_pixel.saturating_mul(16),
+        None => scene.samples_per_pixel,
+    };
+    let mut last_checkpoint = Instant::now();
+    loop {
+        let pass_start = Instant::now();
+        render_pass(&mut film, &scene, &lights, &ids, pass_samples, max_samples);
+        pass += 1;
+        let finished = film
+            .pixels
+            .iter()
+            .all(|p| is_finished(p, &scene, max_samples));
+        if finished {
+            break;
+        }
+        if let Some(target_noise) = options.target_noise {
+            if estimated_noise(&film) <= target_noise {
+                break;
+            }
+        }
+        if let Some(time_limit) = options.time_limit {
+            // Assume the next pass will take as long as this one did.
+            if start.elapsed() + pass_start.elapsed() > time_limit {
+                break;
+            }
+        }
+        if let Some(path) = &options.checkpoint {
+            if last_checkpoint.elapsed() >= options.checkpoint_interval {
+                checkpoint::save(path, &film, pass, fingerprint).expect("error saving checkpoint");
+                last_checkpoint = Instant::now();
+            }
+        }
+        if let Some(progressive) = &scene.progressive {
+            let name = if progressive.snapshots {
+                snapshot_name(filename, pass)
+            } else {
+                filename.to_string()
+            };
+            write_film(&name, &film, &scene, gbuffer.as_ref(), false);
+            println!(
+                "Pass {}: {:.1} samples per pixel, {}ms",
+                pass,
+                film.total_samples() as f64 / film.pixels.len() as f64,
+                start.elapsed().as_millis()
+            );
+        }
+    }
+    if options.has_budget() {
+        println!(
+            "{} passes, {:.1} samples per pixel, estimated noise {:.4}",
+            pass,
+            film.total_samples() as f64 / film.pixels.len() as f64,
+            estimated_noise(&film)
+        );
+    }
+    if let Some(path) = &options.checkpoint {
+        checkpoint::save(path, &film, pass, fingerprint).expect("error saving checkpoint");
+    }
     println!("Frame time: {}ms", start.elapsed().as_millis());
 
-    write_image(filename, &pixels, (image_width, image_height)).expect("error writing image");
+    write_film(filename, &film, &scene, gbuffer.as_ref(), true);
+
+    if let Some(path) = scene
+        .adaptive
+        .as_ref()
+        .and_then(|a| a.sample_count_image.as_ref())
+    {
+        println!(
+            "Average samples per pixel: {:.1}",
+            film.total_samples() as f64 / film.pixels.len() as f64
+        );
+        write_sample_counts(path, &film, scene.samples_per_pixel);
+    }
+}
+
+// Writes how many samples each pixel took, in the format given by the file
+// name. PNG files are scaled so that white is max_samples, while OpenEXR
+// and Radiance HDR files hold the counts themselves.
+fn write_sample_counts(filename: &str, film: &Film, max_samples: u32) {
+    let bounds = (film.width, film.height);
+    match ImageFormat::from_filename(filename) {
+        ImageFormat::Png => write_png(filename, &film.sample_counts_rgb8(max_samples), bounds)
+            .expect("error writing image"),
+        ImageFormat::RadianceHdr => {
+            write_hdr(filename, &film.sample_counts(), bounds).expect("error writing image")
+        }
+        ImageFormat::OpenExr => {
+            let layer = ExrLayer {
+                name: "",
+                channels: &["Y"],
+                values: film.sample_counts(),
+                samples: ExrSamples::Float,
+            };
+            write_exr(filename, &[layer], bounds).expect("error writing image")
+        }
+    }
 }
 
 #[test]
@@ -274,6 +773,361 @@ fn test_render_full_test_scene() {
     render("/tmp/test_scene.png", scene);
 }
 
+#[test]
+fn test_render_adaptive() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 40;
+    scene.height = 30;
+    scene.samples_per_pixel = 64;
+    scene.adaptive = Some(AdaptiveSampling {
+        min_samples: 8,
+        threshold: 0.02,
+        sample_count_image: Some(temp_path("test_scene_samples.png")),
+    });
+    render(&temp_path("test_scene_adaptive.png"), scene);
+    let counts = image::open(temp_path("test_scene_samples.png"))
+        .unwrap()
+        .into_luma8();
+    let levels: Vec<u8> = counts.pixels().map(|p| p.0[0]).collect();
+    // Every pixel took at least the minimum, and some stopped early.
+    assert!(levels.iter().all(|&l| l as u32 >= 255 * 8 / 64));
+    assert!(levels.iter().any(|&l| l < 255));
+
+    // HDR formats hold the counts themselves.
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 40;
+    scene.height = 30;
+    scene.samples_per_pixel = 64;
+    scene.adaptive = Some(AdaptiveSampling {
+        min_samples: 8,
+        threshold: 0.02,
+        sample_count_image: Some(temp_path("test_scene_samples.hdr")),
+    });
+    render(&temp_path("test_scene_adaptive.png"), scene);
+    let counts = image::open(temp_path("test_scene_samples.hdr"))
+        .unwrap()
+        .into_rgb32f();
+    assert!(counts.pixels().all(|p| (8.0..=64.0).contains(&p.0[0])));
+    assert!(counts.pixels().any(|p| p.0[0] < 64.0));
+}
+
+#[test]
+fn test_render_denoised() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let render_roughness = |denoise: Option<Denoise>| {
+        let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+        scene.width = 40;
+        scene.height = 30;
+        scene.samples_per_pixel = 4;
+        scene.denoise = denoise;
+        let filename = temp_path("test_scene_denoised.png");
+        render(&filename, scene);
+        let image = image::open(&filename).unwrap().into_luma8();
+        let levels: Vec<i32> = image.pixels().map(|p| p.0[0] as i32).collect();
+        levels.windows(2).map(|w| (w[0] - w[1]).abs()).sum::<i32>()
+    };
+    // Neighbouring pixels are more alike once the noise is smoothed away.
+    assert!(render_roughness(Some(Denoise::new())) < render_roughness(None) * 3 / 4);
+}
+
+#[test]
+fn test_render_aovs() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 40;
+    scene.height = 30;
+    scene.samples_per_pixel = 8;
+    scene.aovs = vec![Aov::Direct, Aov::Indirect, Aov::Emission, Aov::ObjectId];
+    let filename = temp_path("test_scene_aovs.png");
+    render(&filename, scene);
+    let linear = |name: &str| -> Vec<f64> {
+        let image = image::open(name).unwrap().into_rgb8();
+        image
+            .pixels()
+            .flat_map(|p| p.0.map(|c| (c as f64 / 255.0).powi(2)))
+            .collect()
+    };
+    let beauty = linear(&filename);
+    let direct = linear(&aov_name(&filename, &Aov::Direct));
+    let indirect = linear(&aov_name(&filename, &Aov::Indirect));
+    let emission = linear(&aov_name(&filename, &Aov::Emission));
+    // Without denoising, the parts of the light add up to the image.
+    for i in 0..beauty.len() {
+        let parts = direct[i] + indirect[i] + emission[i];
+        assert!((parts - beauty[i]).abs() < 0.02);
+    }
+    assert!(indirect.iter().any(|&c| c > 0.0));
+    let ids = image::open(aov_name(&filename, &Aov::ObjectId))
+        .unwrap()
+        .into_rgb8();
+    let mut colors: Vec<[u8; 3]> = ids.pixels().map(|p| p.0).collect();
+    colors.sort();
+    colors.dedup();
+    assert!(colors.len() > 2);
+}
+
+#[test]
+fn test_render_hdr_formats() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let filename = |extension: &str| temp_path(&format!("test_scene_formats.{}", extension));
+    for extension in ["png", "hdr", "exr"] {
+        let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+        scene.width = 40;
+        scene.height = 30;
+        scene.samples_per_pixel = 4;
+        scene.aovs = vec![Aov::Depth, Aov::Normal, Aov::ObjectId];
+        render(&filename(extension), scene);
+    }
+    let png = image::open(filename("png")).unwrap().into_rgb8();
+    let hdr = image::open(filename("hdr")).unwrap().into_rgb32f();
+    assert!(Path::new(&aov_name(&filename("hdr"), &Aov::Depth)).exists());
+
+    let exr = exr::prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .first_valid_layer()
+        .all_attributes()
+        .from_file(filename("exr"))
+        .unwrap();
+    let channels = &exr.layer_data.channel_data.list;
+    let names: Vec<String> = channels.iter().map(|c| c.name.to_string()).collect();
+    assert_eq!(
+        names,
+        [
+            "B",
+            "G",
+            "R",
+            "depth.Z",
+            "normal.X",
+            "normal.Y",
+            "normal.Z",
+            "object_id.id"
+        ]
+    );
+    // Both hold the same image as the PNG, before clamping, gamma and
+    // rounding.
+    let red: Vec<f32> = channels[2].sample_data.values_as_f32().collect();
+    for (i, p) in png.pixels().enumerate() {
+        let expected = p.0[0] as f32 / 255.0;
+        assert!((red[i].clamp(0.0, 1.0).sqrt() - expected).abs() < 0.01);
+        let (x, y) = (i as u32 % 40, i as u32 / 40);
+        assert!((hdr.get_pixel(x, y).0[0].min(1.0).sqrt() - expected).abs() < 0.01);
+    }
+    let normal_x: Vec<f32> = channels[4].sample_data.values_as_f32().collect();
+    assert!(normal_x.iter().any(|&x| x < -0.5));
+}
+
+#[test]
+fn test_render_progressive() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 40;
+    scene.height = 30;
+    scene.samples_per_pixel = 12;
+    scene.progressive = Some(Progressive {
+        samples_per_pass: 4,
+        snapshots: true,
+    });
+    let filename = temp_path("progressive.png");
+    let _ = fs::remove_file(snapshot_name(&filename, 3));
+    render(&filename, scene);
+    // The last pass is written to the output, not a snapshot.
+    assert!(fs::metadata(snapshot_name(&filename, 1)).is_ok());
+    assert!(fs::metadata(snapshot_name(&filename, 2)).is_ok());
+    assert!(fs::metadata(snapshot_name(&filename, 3)).is_err());
+    assert!(fs::metadata(&filename).is_ok());
+    assert_eq!(snapshot_name("out", 12), "out_0012");
+}
+
+#[test]
+fn test_render_budgets() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 10;
+    scene.height = 8;
+    scene.samples_per_pixel = 1;
+    let scene_json = serde_json::to_string(&scene).unwrap();
+    let fingerprint = checkpoint::fingerprint(&scene_json);
+    // The passes taken are read back from the checkpoint.
+    let path = temp_path("budgets.checkpoint");
+    let render_budget = |options: RenderOptions| {
+        let options = RenderOptions {
+            checkpoint: Some(path.clone()),
+            ..options
+        };
+        let scene = serde_json::from_str::<Config>(&scene_json).unwrap();
+        render_with_options(&temp_path("budgets.png"), scene, &options);
+        checkpoint::load(&path, fingerprint, 10, 8).unwrap()
+    };
+
+    // The time limit, not samples_per_pixel, decides when to stop.
+    let (film, pass) = render_budget(RenderOptions {
+        time_limit: Some(Duration::from_millis(200)),
+        ..RenderOptions::default()
+    });
+    assert!(pass > 1);
+    assert!(film.pixels.iter().all(|p| p.stats.count > 1));
+
+    // A noise target that is never met stops at the sample cap.
+    let (film, pass) = render_budget(RenderOptions {
+        target_noise: Some(0.0),
+        max_samples: Some(32),
+        ..RenderOptions::default()
+    });
+    assert_eq!(pass, 2);
+    assert!(film.pixels.iter().all(|p| p.stats.count == 32));
+    // Or without one, at 16 times samples_per_pixel.
+    let (film, pass) = render_budget(RenderOptions {
+        target_noise: Some(0.0),
+        ..RenderOptions::default()
+    });
+    assert_eq!(pass, 1);
+    assert!(film.pixels.iter().all(|p| p.stats.count == 16));
+
+    let mut film = Film::new(1, 1);
+    assert_eq!(estimated_noise(&film), 1.0);
+    for _ in 0..100 {
+        film.pixels[0].add(Srgb::new(0.5, 0.5, 0.5));
+    }
+    assert_eq!(estimated_noise(&film), 0.0);
+}
+
+#[test]
+fn test_render_resume() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let scene = || {
+        let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+        scene.width = 16;
+        scene.height = 12;
+        scene.samples_per_pixel = 48;
+        scene
+    };
+    let path = temp_path("resume.checkpoint");
+    let options = RenderOptions {
+        checkpoint: Some(path.clone()),
+        checkpoint_interval: Duration::ZERO,
+        resume: true,
+        ..RenderOptions::default()
+    };
+
+    // Uninterrupted, in three passes of 16 samples.
+    let _ = fs::remove_file(&path);
+    let uninterrupted = temp_path("resume_uninterrupted.png");
+    render_with_options(&uninterrupted, scene(), &options);
+
+    // Killed after saving the checkpoint of the first pass, then resumed.
+    let scene = scene();
+    let fingerprint = checkpoint::fingerprint(&serde_json::to_string(&scene).unwrap());
+    let lights = find_lights(&scene.objects);
+    let ids = material_ids(&scene.objects);
+    let mut film = Film::new(16, 12);
+    render_pass(&mut film, &scene, &lights, &ids, 16, 48);
+    checkpoint::save(&path, &film, 1, fingerprint).unwrap();
+    let resumed = temp_path("resume_resumed.png");
+    render_with_options(&resumed, scene, &options);
+
+    let (film, pass) = checkpoint::load(&path, fingerprint, 16, 12).unwrap();
+    assert_eq!(pass, 3);
+    assert!(film.pixels.iter().all(|p| p.stats.count == 48));
+    assert_eq!(
+        fs::read(&uninterrupted).unwrap(),
+        fs::read(&resumed).unwrap()
+    );
+}
+
+#[test]
+fn test_render_resume_first_run() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 16;
+    scene.height = 12;
+    scene.samples_per_pixel = 16;
+    let fingerprint = checkpoint::fingerprint(&serde_json::to_string(&scene).unwrap());
+
+    // On the first run there is no checkpoint to resume from yet.
+    let path = temp_path("first_run.checkpoint");
+    let _ = fs::remove_file(&path);
+    let options = RenderOptions {
+        checkpoint: Some(path.clone()),
+        resume: true,
+        ..RenderOptions::default()
+    };
+    render_with_options(&temp_path("first_run.png"), scene, &options);
+    let (film, pass) = checkpoint::load(&path, fingerprint, 16, 12).unwrap();
+    assert_eq!(pass, 1);
+    assert!(film.pixels.iter().all(|p| p.stats.count == 16));
+}
+
+#[test]
+fn test_render_deterministic() {
+    let json = fs::read("data/test_scene.json").expect("Unable to read file");
+    let render_with_threads = |threads: usize, seed: u64| {
+        let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+        scene.width = 24;
+        scene.height = 18;
+        scene.samples_per_pixel = 4;
+        scene.seed = seed;
+        // Samples are splatted into neighbouring rows, which may be rendered
+        // by other threads.
+        scene.filter = PixelFilter::Mitchell {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        };
+        let filename = temp_path(&format!("deterministic_{}_{}.png", threads, seed));
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| render(&filename, scene));
+        fs::read(&filename).unwrap()
+    };
+    let single = render_with_threads(1, 7);
+    assert_eq!(single, render_with_threads(4, 7));
+    assert_ne!(single, render_with_threads(1, 8));
+}
+
+#[test]
+fn test_render_toon_scene() {
+    let json = fs::read("data/toon_scene.json").expect("Unable to read file");
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 80;
+    scene.height = 45;
+    render(&temp_path("toon_scene.png"), scene);
+
+    // With one sample per pixel and no outline, every pixel of the blue
+    // sphere, the third object, is one of its four bands.
+    let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+    scene.width = 80;
+    scene.height = 45;
+    scene.samples_per_pixel = 1;
+    scene.outline = None;
+    scene.aovs = vec![Aov::ObjectId];
+    scene.exr_precision = ExrPrecision::Float;
+    let filename = temp_path("toon_scene.exr");
+    render(&filename, scene);
+    let exr = exr::prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .first_valid_layer()
+        .all_attributes()
+        .from_file(filename)
+        .unwrap();
+    let channels = &exr.layer_data.channel_data.list;
+    let values = |c: usize| -> Vec<f32> { channels[c].sample_data.values_as_f32().collect() };
+    let (blue, ids) = (values(0), values(3));
+    let mut levels: Vec<f32> = (0..ids.len())
+        .filter(|&i| ids[i] == 3.0)
+        .map(|i| blue[i])
+        .collect();
+    levels.sort_by(f32::total_cmp);
+    levels.dedup();
+    assert!((2..=4).contains(&levels.len()));
+}
+
 #[test]
 fn test_render_full_cover_scene() {
     let json = fs::read("data/cover_scene.json").expect("Unable to read file");
@@ -0,0 +1,34 @@
+use rand::SeedableRng;
+use rand_pcg::Pcg32;
+
+#[cfg(test)]
+use rand::Rng;
+
+// The random number generator behind the independent sampler. Every sample of
+// every pixel gets its own generator, seeded from the scene seed, the pixel and
+// the sample number, so a render is the same however it is split between
+// threads, passes or resumed runs.
+pub type SampleRng = Pcg32;
+
+// SplitMix64, to spread nearby inputs over the whole seed space.
+pub fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+pub fn sample_rng(seed: u64, pixel: usize, sample: u32) -> SampleRng {
+    let key = mix(mix(mix(seed) ^ pixel as u64) ^ sample as u64);
+    SampleRng::seed_from_u64(key)
+}
+
+#[test]
+fn test_sample_rng() {
+    let a: Vec<u32> = (0..4).map(|_| sample_rng(1, 2, 3).gen()).collect();
+    assert!(a.iter().all(|&x| x == a[0]));
+    let first: u64 = sample_rng(1, 2, 3).gen();
+    assert_ne!(first, sample_rng(0, 2, 3).gen::<u64>());
+    assert_ne!(first, sample_rng(1, 3, 3).gen::<u64>());
+    assert_ne!(first, sample_rng(1, 2, 4).gen::<u64>());
+}
@@ -0,0 +1,438 @@
+use rand::{Error, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::rng::{mix, sample_rng, SampleRng};
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+// How the random numbers for each sample are chosen. Everything but
+// Independent spreads the samples of a pixel more evenly than chance would,
+// which gives less noise for the same number of samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SamplerKind {
+    // Uniform random numbers.
+    #[default]
+    Independent,
+    // Correlated multi-jittered samples, stratified over samples_per_pixel.
+    Stratified,
+    // The Halton sequence, randomly shifted for each pixel.
+    Halton,
+    // The first two dimensions of the Sobol sequence, with a different Owen
+    // scrambling and shuffle for each pair of dimensions.
+    Sobol,
+    // The same scrambled Sobol samples in every pixel, offset by a blue noise
+    // mask so that the remaining error looks like fine grain.
+    BlueNoise,
+}
+
+// Supplies the random numbers for one sample of one pixel. The numbers are
+// taken in pairs from the chosen sequence, so the pixel position, and the
+// first bounce directions, are spread out in two dimensions. It is also a
+// random number generator, where every number drawn is the next dimension.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    kind: SamplerKind,
+    seed: u64,
+    x: usize,
+    y: usize,
+    sample: u32,
+    samples_per_pixel: u32,
+    dimension: u32,
+    pending: Option<f64>,
+    rng: SampleRng,
+}
+
+impl Sampler {
+    pub fn new(
+        kind: SamplerKind,
+        seed: u64,
+        (x, y): (usize, usize),
+        width: usize,
+        sample: u32,
+        samples_per_pixel: u32,
+    ) -> Sampler {
+        Sampler {
+            kind,
+            seed,
+            x,
+            y,
+            sample,
+            samples_per_pixel: samples_per_pixel.max(1),
+            dimension: 0,
+            pending: None,
+            rng: sample_rng(seed, y * width + x, sample),
+        }
+    }
+
+    // Plain random numbers, for sampling outside of a render.
+    pub fn independent(seed: u64) -> Sampler {
+        Sampler::new(SamplerKind::Independent, seed, (0, 0), 1, 0, 1)
+    }
+
+    // The next dimension of this sample, in [0, 1).
+    pub fn next_1d(&mut self) -> f64 {
+        if let Some(value) = self.pending.take() {
+            return value;
+        }
+        let (a, b) = self.next_2d();
+        self.pending = Some(b);
+        a
+    }
+
+    // The next pair of dimensions of this sample.
+    pub fn next_2d(&mut self) -> (f64, f64) {
+        let pair = self.dimension;
+        self.dimension += 1;
+        let hash = self.hash(pair);
+        match self.kind {
+            SamplerKind::Independent => (self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => {
+                if self.sample >= self.samples_per_pixel {
+                    return (self.rng.gen(), self.rng.gen());
+                }
+                correlated_multi_jitter(self.sample, self.samples_per_pixel, hash as u32)
+            }
+            SamplerKind::Halton => {
+                let Some(bases) = PRIMES.get(2 * pair as usize..2 * pair as usize + 2) else {
+                    return (self.rng.gen(), self.rng.gen());
+                };
+                let shift = (to_unit(hash as u32), to_unit((hash >> 32) as u32));
+                (
+                    (radical_inverse(self.sample, bases[0]) + shift.0).fract(),
+                    (radical_inverse(self.sample, bases[1]) + shift.1).fract(),
+                )
+            }
+            SamplerKind::Sobol => owen_sobol(self.sample, hash),
+            SamplerKind::BlueNoise => {
+                // Scrambled the same way in every pixel.
+                let global = mix(mix(self.seed) ^ pair as u64);
+                let (a, b) = owen_sobol(self.sample, global);
+                let mask = blue_noise_mask();
+                let offset = |salt: u64| {
+                    let h = mix(global ^ salt);
+                    let x = (self.x + (h as usize & 0xffff)) % MASK_SIZE;
+                    let y = (self.y + ((h >> 16) as usize & 0xffff)) % MASK_SIZE;
+                    mask[y * MASK_SIZE + x] as f64
+                };
+                ((a + offset(1)).fract(), (b + offset(2)).fract())
+            }
+        }
+    }
+
+    fn hash(&self, pair: u32) -> u64 {
+        mix(mix(mix(mix(self.seed) ^ self.x as u64) ^ self.y as u64) ^ pair as u64)
+    }
+}
+
+// Each number drawn through the Rng interface is one dimension, scaled to the
+// full range of the integer type, so gen::<f64>() is the dimension itself.
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_1d() * 4294967296.0) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_1d() * 18446744073709551616.0) as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+fn to_unit(bits: u32) -> f64 {
+    bits as f64 / 4294967296.0
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+fn radical_inverse(mut index: u32, base: u32) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    result
+}
+
+// Kensler's hashed permutation: element i of a random permutation of 0..l.
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+fn hash_unit(i: u32, p: u32) -> f64 {
+    to_unit(mix(((p as u64) << 32) | i as u64) as u32)
+}
+
+// Kensler's correlated multi-jittered sampling: sample s of n lands in its own
+// cell of an m by n/m grid, and in its own 1/n strip along each axis.
+fn correlated_multi_jitter(s: u32, n: u32, p: u32) -> (f64, f64) {
+    let m = (n as f64).sqrt().ceil() as u32;
+    let rows = n.div_ceil(m);
+    let s = permute(s, n, p.wrapping_mul(0x51633e2d));
+    let sx = permute(s % m, m, p.wrapping_mul(0x68bc21eb));
+    let sy = permute(s / m, rows, p.wrapping_mul(0x02e5be93));
+    let jx = hash_unit(s, p.wrapping_mul(0x967a889b));
+    let jy = hash_unit(s, p.wrapping_mul(0x368cc8b7));
+    (
+        ((sx as f64 + (sy as f64 + jx) / rows as f64) / m as f64).min(ONE_MINUS_EPSILON),
+        ((s as f64 + jy) / n as f64).min(ONE_MINUS_EPSILON),
+    )
+}
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// A random nested uniform (Owen) scrambling of the bits of v, from most to
+// least significant, after Laine and Karras.
+fn owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+// The second dimension of the Sobol sequence. Its generator matrix is
+// Pascal's triangle modulo 2.
+fn sobol_second(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut column = 1u32 << 31;
+    while index > 0 {
+        if index & 1 == 1 {
+            result ^= column;
+        }
+        index >>= 1;
+        column ^= column >> 1;
+    }
+    result
+}
+
+// The shuffled and Owen-scrambled first two Sobol dimensions (Burley 2020).
+fn owen_sobol(sample: u32, hash: u64) -> (f64, f64) {
+    let index = owen_scramble(sample, hash as u32);
+    let hash = mix(hash);
+    (
+        to_unit(owen_scramble(index.reverse_bits(), hash as u32)),
+        to_unit(owen_scramble(sobol_second(index), (hash >> 32) as u32)),
+    )
+}
+
+const MASK_SIZE: usize = 64;
+
+// A tileable blue noise mask, made with Ulichney's void and cluster method.
+// Each pixel holds its rank in [0, 1), and nearby pixels have very different
+// ranks.
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let n = MASK_SIZE * MASK_SIZE;
+        let kernel: Vec<f32> = (0..n)
+            .map(|i| {
+                let wrap = |d: usize| d.min(MASK_SIZE - d) as f32;
+                let (dx, dy) = (wrap(i % MASK_SIZE), wrap(i / MASK_SIZE));
+                (-(dx * dx + dy * dy) / (2.0 * 1.5 * 1.5)).exp()
+            })
+            .collect();
+        let splat = |energy: &mut [f32], at: usize, sign: f32| {
+            let (ax, ay) = (at % MASK_SIZE, at / MASK_SIZE);
+            for (i, e) in energy.iter_mut().enumerate() {
+                let dx = (i % MASK_SIZE + MASK_SIZE - ax) % MASK_SIZE;
+                let dy = (i / MASK_SIZE + MASK_SIZE - ay) % MASK_SIZE;
+                *e += sign * kernel[dy * MASK_SIZE + dx];
+            }
+        };
+        // The tightest cluster is the set pixel with most energy, and the
+        // largest void the unset pixel with least.
+        let tightest = |pattern: &[bool], energy: &[f32]| {
+            (0..n)
+                .filter(|&i| pattern[i])
+                .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap()
+        };
+        let largest_void = |pattern: &[bool], energy: &[f32]| {
+            (0..n)
+                .filter(|&i| !pattern[i])
+                .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap()
+        };
+
+        // Start from random points, and move them from clusters into voids
+        // until they are evenly spread.
+        let mut rng = sample_rng(0, 0, 0);
+        let mut pattern = vec![false; n];
+        let mut energy = vec![0.0; n];
+        let initial = n / 10;
+        while pattern.iter().filter(|&&p| p).count() < initial {
+            let i = rng.gen_range(0..n);
+            if !pattern[i] {
+                pattern[i] = true;
+                splat(&mut energy, i, 1.0);
+            }
+        }
+        loop {
+            let cluster = tightest(&pattern, &energy);
+            pattern[cluster] = false;
+            splat(&mut energy, cluster, -1.0);
+            let void = largest_void(&pattern, &energy);
+            pattern[void] = true;
+            splat(&mut energy, void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        // Rank the initial points by removing clusters, then the rest by
+        // filling voids.
+        let mut rank = vec![0; n];
+        let (mut removing, mut removing_energy) = (pattern.clone(), energy.clone());
+        for r in (0..initial).rev() {
+            let cluster = tightest(&removing, &removing_energy);
+            removing[cluster] = false;
+            splat(&mut removing_energy, cluster, -1.0);
+            rank[cluster] = r;
+        }
+        for r in initial..n {
+            let void = largest_void(&pattern, &energy);
+            pattern[void] = true;
+            splat(&mut energy, void, 1.0);
+            rank[void] = r;
+        }
+        rank.iter().map(|&r| (r as f32 + 0.5) / n as f32).collect()
+    })
+}
+
+#[cfg(test)]
+fn samples(kind: SamplerKind, count: u32, pair: u32) -> Vec<(f64, f64)> {
+    (0..count)
+        .map(|s| {
+            let mut sampler = Sampler::new(kind, 3, (5, 7), 16, s, count);
+            for _ in 0..pair {
+                sampler.next_2d();
+            }
+            sampler.next_2d()
+        })
+        .collect()
+}
+
+// Whether every cell of a cols by rows grid holds exactly one point.
+#[cfg(test)]
+fn one_per_cell(points: &[(f64, f64)], cols: usize, rows: usize) -> bool {
+    let mut cells = vec![0; cols * rows];
+    for &(a, b) in points {
+        cells[(b * rows as f64) as usize * cols + (a * cols as f64) as usize] += 1;
+    }
+    cells.iter().all(|&c| c == 1)
+}
+
+#[test]
+fn test_stratified_sampler() {
+    for pair in 0..3 {
+        let points = samples(SamplerKind::Stratified, 16, pair);
+        assert!(one_per_cell(&points, 4, 4));
+        assert!(one_per_cell(&points, 16, 1));
+        assert!(one_per_cell(&points, 1, 16));
+    }
+    // Also for counts that are not square.
+    let points = samples(SamplerKind::Stratified, 12, 0);
+    assert!(one_per_cell(&points, 12, 1));
+    assert!(one_per_cell(&points, 1, 12));
+}
+
+#[test]
+fn test_sobol_sampler() {
+    for pair in 0..3 {
+        let points = samples(SamplerKind::Sobol, 16, pair);
+        // A (0, 4, 2)-net: one point in every elementary interval.
+        for (cols, rows) in [(1, 16), (2, 8), (4, 4), (8, 2), (16, 1)] {
+            assert!(one_per_cell(&points, cols, rows));
+        }
+    }
+    assert_ne!(
+        samples(SamplerKind::Sobol, 4, 0),
+        samples(SamplerKind::Sobol, 4, 1)
+    );
+}
+
+#[test]
+fn test_halton_sampler() {
+    assert_approx_eq!(radical_inverse(5, 2), 0.625);
+    assert_approx_eq!(radical_inverse(5, 3), 7.0 / 9.0);
+    let mut sampler = Sampler::new(SamplerKind::Halton, 0, (0, 0), 1, 0, 1);
+    for _ in 0..100 {
+        let value = sampler.next_1d();
+        assert!((0.0..1.0).contains(&value));
+    }
+}
+
+#[test]
+fn test_blue_noise_sampler() {
+    let mask = blue_noise_mask();
+    let mut sorted = mask.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    sorted.dedup();
+    assert_eq!(sorted.len(), MASK_SIZE * MASK_SIZE);
+    // Neighbours differ by a third on average for white noise, and by more
+    // for blue noise.
+    let difference: f32 = (0..mask.len())
+        .map(|i| (mask[i] - mask[(i + 1) % mask.len()]).abs())
+        .sum::<f32>()
+        / mask.len() as f32;
+    assert!(difference > 0.4);
+
+    let a = Sampler::new(SamplerKind::BlueNoise, 0, (0, 0), 2, 0, 1).next_2d();
+    let b = Sampler::new(SamplerKind::BlueNoise, 0, (1, 0), 2, 0, 1).next_2d();
+    assert_ne!(a, b);
+}
+
+#[test]
+fn test_sampler_as_rng() {
+    let mut a = Sampler::new(SamplerKind::Sobol, 1, (2, 3), 4, 5, 8);
+    let mut b = a.clone();
+    assert_approx_eq!(a.gen::<f64>(), b.next_1d());
+    assert_approx_eq!(a.gen::<f64>(), b.next_1d());
+    assert_eq!(a.gen::<f64>() >= 0.5, b.next_1d() >= 0.5);
+    assert_eq!(a.gen::<bool>(), b.next_1d() >= 0.5);
+}
@@ -5,7 +5,11 @@ use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Hittable;
 use crate::ray::Ray;
+use crate::textures::is_default;
+use crate::textures::NormalMap;
 
+#[cfg(test)]
+use crate::materials::Cutout;
 #[cfg(test)]
 use crate::materials::Glass;
 #[cfg(test)]
@@ -13,6 +17,14 @@ use crate::materials::Lambertian;
 #[cfg(test)]
 use crate::materials::Texture;
 #[cfg(test)]
+use crate::temp_path;
+#[cfg(test)]
+use crate::textures::Procedural;
+#[cfg(test)]
+use crate::textures::TextureRef;
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+#[cfg(test)]
 use palette::Srgb;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -20,6 +32,15 @@ pub struct Sphere {
     pub center: Point3D,
     pub radius: f64,
     pub material: Material,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<NormalMap>,
+    // If provided, used instead of material where rays hit the inside.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub back_material: Option<Material>,
+    // A thin shell, like a leaf or a sheet of paper, rather than a solid.
+    // Glass and Translucent materials let light through it differently.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub thin: bool,
 }
 
 impl Sphere {
@@ -28,10 +49,31 @@ impl Sphere {
             center,
             radius,
             material,
+            normal_map: None,
+            back_material: None,
+            thin: false,
+        }
+    }
+
+    fn material(&self, front_face: bool) -> &Material {
+        match &self.back_material {
+            Some(back_material) if !front_face => back_material,
+            _ => &self.material,
         }
     }
 }
 
+// The direction of increasing u around a sphere with outward normal n.
+fn tangent_from_sphere_normal(n: Point3D) -> Point3D {
+    let t = Point3D::new(n.z(), 0.0, -n.x());
+    if t.near_zero() {
+        // At the poles every direction is equally valid.
+        Point3D::new(1.0, 0.0, 0.0)
+    } else {
+        t.unit_vector()
+    }
+}
+
 fn u_v_from_sphere_hit_point(hit_point_on_sphere: Point3D) -> (f64, f64) {
     let n = hit_point_on_sphere.unit_vector();
     let x = n.x();
@@ -43,7 +85,7 @@ fn u_v_from_sphere_hit_point(hit_point_on_sphere: Point3D) -> (f64, f64) {
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let oc = ray.origin - self.center;
         let a = ray.direction.length_squared();
         let half_b = oc.dot(&ray.direction);
@@ -62,15 +104,41 @@ impl Hittable for Sphere {
 
                     let (u, v) = u_v_from_sphere_hit_point(p - self.center);
 
-                    return Some(HitRecord {
+                    // The ray's cone meets the surface at an angle, stretching
+                    // its footprint. A full turn around the sphere is 1.0 in u,
+                    // and half a turn is 1.0 in v, so on a 2:1 image, whose
+                    // mip-maps are chosen by its width, either gives the same
+                    // number of texels.
+                    let cos = ray.direction.unit_vector().dot(&normal).abs().max(0.05);
+                    let width = ray.spread * root * ray.direction.length() / cos;
+                    let footprint = width / (2.0 * std::f64::consts::PI * self.radius);
+
+                    // Seen from inside, the normal is flipped, so the
+                    // bitangent is too, keeping the frame right-handed.
+                    let tangent = tangent_from_sphere_normal(normal);
+                    let normal = if front_face { normal } else { -normal };
+                    let bitangent = normal.cross(&tangent);
+
+                    let mut hit_record = HitRecord {
                         t: *root,
                         point: p,
-                        normal: if front_face { normal } else { -normal },
+                        normal,
+                        tangent,
+                        bitangent,
                         front_face,
-                        material: &self.material,
+                        material: self.material(front_face),
                         u,
                         v,
-                    });
+                        footprint,
+                        thin: self.thin,
+                    };
+                    if hit_record.material.is_cut_out(&hit_record) {
+                        continue;
+                    }
+                    if let Some(normal_map) = &self.normal_map {
+                        normal_map.perturb(&mut hit_record);
+                    }
+                    return Some(hit_record);
                 }
             }
         }
@@ -87,14 +155,190 @@ fn test_sphere_hit() {
     assert_eq!(hit.unwrap().t, 4.0);
 }
 
+#[test]
+fn test_sphere_footprint() {
+    let sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        2.0,
+        Material::Glass(Glass::new(1.5)),
+    );
+    let mut ray = Ray::new(Point3D::new(0.0, 0.0, -5.0), Point3D::new(0.0, 0.0, 1.0));
+    ray.spread = 0.01;
+    // Head on, 3 units away, the cone is 0.03 wide, out of 4 pi around.
+    let hit = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap();
+    assert_approx_eq!(hit.footprint, 0.03 / (4.0 * std::f64::consts::PI));
+}
+
+#[test]
+fn test_sphere_tangent_frame() {
+    let sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        2.0,
+        Material::Glass(Glass::new(1.5)),
+    );
+    let ray = Ray::new(Point3D::new(1.0, 1.0, -5.0), Point3D::new(0.0, 0.0, 1.0));
+    let hit = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap();
+    assert_approx_eq!(hit.tangent.length(), 1.0);
+    assert_approx_eq!(hit.bitangent.length(), 1.0);
+    assert_approx_eq!(hit.tangent.dot(&hit.normal), 0.0);
+    assert_approx_eq!(hit.bitangent.dot(&hit.normal), 0.0);
+    assert_approx_eq!(hit.tangent.dot(&hit.bitangent), 0.0);
+    // Moving along the tangent frame increases u and v.
+    let step = 1e-4;
+    let along_u = sphere.center + (hit.point + hit.tangent * step).unit_vector() * 2.0;
+    let along_v = sphere.center + (hit.point + hit.bitangent * step).unit_vector() * 2.0;
+    assert!(u_v_from_sphere_hit_point(along_u).0 > hit.u);
+    assert!(u_v_from_sphere_hit_point(along_v).1 > hit.v);
+}
+
+#[test]
+fn test_sphere_tangent_frame_inside() {
+    let sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        2.0,
+        Material::Glass(Glass::new(1.5)),
+    );
+    let ray = Ray::new(Point3D::new(0.5, 0.5, 0.0), Point3D::new(0.0, 0.0, 1.0));
+    let hit = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap();
+    assert!(!hit.front_face);
+    // The frame is right-handed on both sides.
+    let n = hit.tangent.cross(&hit.bitangent);
+    assert_approx_eq!(n.dot(&hit.normal), 1.0);
+}
+
+#[test]
+fn test_flat_normal_maps() {
+    let mut sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Glass(Glass::new(1.5)),
+    );
+    let ray = Ray::new(Point3D::new(0.3, 0.2, -5.0), Point3D::new(0.0, 0.0, 1.0));
+    let expected = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+
+    sphere.normal_map = Some(NormalMap::Tangent {
+        texture: TextureRef::from(Srgb::new(0.5, 0.5, 1.0)),
+        strength: 1.0,
+    });
+    let actual = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+    assert_approx_eq!(actual.x(), expected.x());
+    assert_approx_eq!(actual.y(), expected.y());
+    assert_approx_eq!(actual.z(), expected.z());
+
+    sphere.normal_map = Some(NormalMap::Bump {
+        height: TextureRef::from(0.3),
+        strength: 5.0,
+    });
+    let actual = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+    assert_approx_eq!(actual.x(), expected.x());
+    assert_approx_eq!(actual.y(), expected.y());
+    assert_approx_eq!(actual.z(), expected.z());
+}
+
+#[test]
+fn test_solid_bump_map() {
+    let mut sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Glass(Glass::new(1.5)),
+    );
+    let ray = Ray::new(Point3D::new(0.3, 0.2, -5.0), Point3D::new(0.0, 0.0, 1.0));
+    let expected = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+
+    // Noise only depends on the point, not on (u, v).
+    sphere.normal_map = Some(NormalMap::Bump {
+        height: TextureRef::Procedural(Procedural::Noise {
+            color: Srgb::new(1.0, 1.0, 1.0),
+            scale: 4.0,
+            octaves: 4,
+        }),
+        strength: 1.0,
+    });
+    let actual = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+    assert!((actual - expected).length() > 0.01);
+}
+
+#[test]
+fn test_flat_normal_map_image() {
+    // An 8 bit normal map is data, so mid grey must not be decoded as sRGB.
+    let flat = image::ImageBuffer::from_pixel(2, 2, image::Rgb([128u8, 128, 255]));
+    let path = temp_path("test_flat_normal_map.png");
+    flat.save(&path).unwrap();
+    let json = format!(
+        r#"{{"Tangent":{{"texture":{{"image":"{}"}},"strength":1.0}}}}"#,
+        path
+    );
+
+    let mut sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Glass(Glass::new(1.5)),
+    );
+    let ray = Ray::new(Point3D::new(0.3, 0.2, -5.0), Point3D::new(0.0, 0.0, 1.0));
+    let expected = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+    sphere.normal_map = Some(serde_json::from_str(&json).unwrap());
+    let actual = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().normal;
+    // 128 is just above half way, so allow for a slight tilt.
+    assert_approx_eq!(actual.x(), expected.x(), 0.01);
+    assert_approx_eq!(actual.y(), expected.y(), 0.01);
+    assert_approx_eq!(actual.z(), expected.z(), 0.01);
+}
+
+#[test]
+fn test_cutout_sphere_hit() {
+    let black = Srgb::new(0.0, 0.0, 0.0);
+    let white = Srgb::new(1.0, 1.0, 1.0);
+    let material = Material::Cutout(Cutout::new(
+        Material::Glass(Glass::new(1.5)),
+        TextureRef::Procedural(Procedural::Checker {
+            even: black,
+            odd: white,
+            scale: 2.0,
+        }),
+    ));
+    let sphere = Sphere::new(Point3D::new(0.0, 0.0, 0.0), 1.0, material);
+
+    // The near side (u = 0.5, v = 0.5) is transparent, so the ray goes on
+    // to hit the opaque far side (u = 1.0, v = 0.5).
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 5.0), Point3D::new(0.0, 0.0, -1.0));
+    let hit = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap();
+    assert_eq!(hit.t, 6.0);
+    assert!(!hit.front_face);
+
+    // Just below the equator the near side is opaque.
+    let ray = Ray::new(Point3D::new(0.0, -0.1, 5.0), Point3D::new(0.0, 0.0, -1.0));
+    let hit = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap();
+    assert!(hit.t < 5.0);
+    assert!(hit.front_face);
+}
+
+#[test]
+fn test_two_sided_sphere() {
+    let mut sphere = Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(1.0, 0.0, 0.0))),
+    );
+    sphere.back_material = Some(Material::Glass(Glass::new(1.5)));
+    let ray = Ray::new(Point3D::new(0.0, 0.0, -5.0), Point3D::new(0.0, 0.0, 1.0));
+    let outside = sphere.hit(&ray, 0.0, f64::INFINITY).unwrap();
+    assert!(matches!(outside.material, Material::Lambertian(_)));
+    assert!(!outside.thin);
+    let inside = sphere.hit(&ray, 5.0, f64::INFINITY).unwrap();
+    assert!(matches!(inside.material, Material::Glass(_)));
+
+    let json = r#"{"center":{"x":0.0,"y":0.0,"z":0.0},"radius":1.0,"material":{"Lambertian":{"albedo":[0.5,0.5,0.5]}},"back_material":{"Lambertian":{"albedo":[0.1,0.1,0.1]}},"thin":true}"#;
+    let sphere = serde_json::from_str::<Sphere>(json).unwrap();
+    assert!(sphere.hit(&ray, 0.0, f64::INFINITY).unwrap().thin);
+    assert_eq!(json, serde_json::to_string(&sphere).unwrap());
+}
+
 #[test]
 fn test_to_json() {
     let sphere = Sphere::new(
         Point3D::new(0.0, 0.0, 0.0),
         1.0,
-        Material::Lambertian(Lambertian::new(Srgb::new(
-            0.5 as f32, 0.5 as f32, 0.5 as f32,
-        ))),
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
     );
     let serialized = serde_json::to_string(&sphere).unwrap();
     assert_eq!(
@@ -109,7 +353,7 @@ fn test_to_json() {
         Point3D::new(0.0, 0.0, 0.0),
         1.0,
         Material::Texture(Texture::new(
-            Srgb::new(0.5 as f32, 0.5 as f32, 0.5 as f32),
+            Srgb::new(0.5, 0.5, 0.5),
             "data/earth.jpg",
             0.0,
         )),
@@ -117,17 +361,13 @@ fn test_to_json() {
 
     let tserialized = serde_json::to_string(&textured_sphere).unwrap();
     assert_eq!(
-        "{\"center\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"radius\":1.0,\"material\":{\"Texture\":{\"albedo\":[0.5,0.5,0.5],\"pixels\":\"/tmp/texture.jpg\",\"width\":2048,\"height\":1024,\"h_offset\":0.0}}}",
+        "{\"center\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"radius\":1.0,\"material\":{\"Texture\":{\"albedo\":[0.5,0.5,0.5],\"pixels\":\"data/earth.jpg\",\"width\":2048,\"height\":1024,\"h_offset\":0.0}}}",
         tserialized,
     );
 
-    let tex = Texture::new(
-        Srgb::new(0.5 as f32, 0.5 as f32, 0.5 as f32),
-        "data/earth.jpg",
-        0.0,
-    );
+    let tex = Texture::new(Srgb::new(0.5, 0.5, 0.5), "data/earth.jpg", 0.0);
     let tloadable = "{\"center\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"radius\":1.0,\"material\":{\"Texture\":{\"albedo\":[0.5,0.5,0.5],\"pixels\":\"data/earth.jpg\",\"width\":2048,\"height\":1024,\"h_offset\":0.0}}}";
-    let loaded = serde_json::from_str::<Sphere>(&tloadable).unwrap();
+    let loaded = serde_json::from_str::<Sphere>(tloadable).unwrap();
     match loaded.material {
         Material::Texture(ref t) => {
             assert_eq!(t.pixels, tex.pixels);
@@ -0,0 +1,879 @@
+use palette::Srgb;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use crate::cache::{file_key, load_shared, SharedCache};
+use crate::materials::SrgbAsArray;
+use crate::point3d::Point3D;
+use crate::ray::HitRecord;
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+#[cfg(test)]
+use crate::temp_path;
+
+// Ken Perlin's reference permutation, used so that noise is identical
+// between runs and machines.
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69,
+    142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219,
+    203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76,
+    132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173,
+    186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206,
+    59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163,
+    70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232,
+    178, 185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162,
+    241, 81, 51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204,
+    176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141,
+    128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+fn perm(i: i64) -> i64 {
+    PERMUTATION[(i & 255) as usize] as i64
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+fn grad(hash: i64, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+// Improved Perlin noise, in the range [-1, 1].
+pub fn perlin(p: &Point3D) -> f64 {
+    let (xf, yf, zf) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (xi, yi, zi) = (xf as i64, yf as i64, zf as i64);
+    let (x, y, z) = (p.x() - xf, p.y() - yf, p.z() - zf);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a = perm(xi) + yi;
+    let aa = perm(a) + zi;
+    let ab = perm(a + 1) + zi;
+    let b = perm(xi + 1) + yi;
+    let ba = perm(b) + zi;
+    let bb = perm(b + 1) + zi;
+
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, grad(perm(aa), x, y, z), grad(perm(ba), x - 1.0, y, z)),
+            lerp(
+                u,
+                grad(perm(ab), x, y - 1.0, z),
+                grad(perm(bb), x - 1.0, y - 1.0, z),
+            ),
+        ),
+        lerp(
+            v,
+            lerp(
+                u,
+                grad(perm(aa + 1), x, y, z - 1.0),
+                grad(perm(ba + 1), x - 1.0, y, z - 1.0),
+            ),
+            lerp(
+                u,
+                grad(perm(ab + 1), x, y - 1.0, z - 1.0),
+                grad(perm(bb + 1), x - 1.0, y - 1.0, z - 1.0),
+            ),
+        ),
+    )
+}
+
+// Fractal Brownian motion: octaves of Perlin noise, each at twice the
+// frequency and half the amplitude of the previous one.
+pub fn fbm(p: &Point3D, octaves: u32) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut point = *p;
+    for _ in 0..octaves {
+        sum += amplitude * perlin(&point);
+        amplitude *= 0.5;
+        point = point * 2.0;
+    }
+    sum
+}
+
+// Like fbm, but summing the absolute value of each octave.
+pub fn turbulence(p: &Point3D, octaves: u32) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut point = *p;
+    for _ in 0..octaves {
+        sum += amplitude * perlin(&point).abs();
+        amplitude *= 0.5;
+        point = point * 2.0;
+    }
+    sum
+}
+
+fn blend(a: Srgb, b: Srgb, t: f64) -> Srgb {
+    let t = t.clamp(0.0, 1.0) as f32;
+    Srgb::new(
+        a.red + t * (b.red - a.red),
+        a.green + t * (b.green - a.green),
+        a.blue + t * (b.blue - a.blue),
+    )
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum Procedural {
+    // Checkerboard in texture (u, v) space, with scale squares per unit.
+    Checker {
+        #[serde_as(as = "SrgbAsArray")]
+        even: Srgb,
+        #[serde_as(as = "SrgbAsArray")]
+        odd: Srgb,
+        scale: f64,
+    },
+    // Checkerboard of cubes in world space, with scale cubes per unit.
+    SolidChecker {
+        #[serde_as(as = "SrgbAsArray")]
+        even: Srgb,
+        #[serde_as(as = "SrgbAsArray")]
+        odd: Srgb,
+        scale: f64,
+    },
+    // Fractal Perlin noise, scaling color by the noise value.
+    Noise {
+        #[serde_as(as = "SrgbAsArray")]
+        color: Srgb,
+        scale: f64,
+        octaves: u32,
+    },
+    // Sine wave veins along z, displaced by turbulence.
+    Marble {
+        #[serde_as(as = "SrgbAsArray")]
+        base: Srgb,
+        #[serde_as(as = "SrgbAsArray")]
+        vein: Srgb,
+        scale: f64,
+        turbulence: f64,
+        octaves: u32,
+    },
+    // Concentric rings around the y axis, perturbed by noise.
+    Wood {
+        #[serde_as(as = "SrgbAsArray")]
+        light: Srgb,
+        #[serde_as(as = "SrgbAsArray")]
+        dark: Srgb,
+        scale: f64,
+        rings: f64,
+        octaves: u32,
+    },
+}
+
+impl Procedural {
+    pub fn value(&self, u: f64, v: f64, p: &Point3D) -> Srgb {
+        match *self {
+            Procedural::Checker { even, odd, scale } => {
+                let sum = (u * scale).floor() as i64 + (v * scale).floor() as i64;
+                if sum.rem_euclid(2) == 0 {
+                    even
+                } else {
+                    odd
+                }
+            }
+            Procedural::SolidChecker { even, odd, scale } => {
+                let sum = (p.x() * scale).floor() as i64
+                    + (p.y() * scale).floor() as i64
+                    + (p.z() * scale).floor() as i64;
+                if sum.rem_euclid(2) == 0 {
+                    even
+                } else {
+                    odd
+                }
+            }
+            Procedural::Noise {
+                color,
+                scale,
+                octaves,
+            } => {
+                let n = 0.5 * (1.0 + fbm(&(*p * scale), octaves)).clamp(0.0, 2.0);
+                blend(Srgb::new(0.0, 0.0, 0.0), color, n)
+            }
+            Procedural::Marble {
+                base,
+                vein,
+                scale,
+                turbulence: amount,
+                octaves,
+            } => {
+                let q = *p * scale;
+                let t = 0.5 * (1.0 + (q.z() + amount * turbulence(&q, octaves)).sin());
+                blend(vein, base, t)
+            }
+            Procedural::Wood {
+                light,
+                dark,
+                scale,
+                rings,
+                octaves,
+            } => {
+                let q = *p * scale;
+                let r = (q.x() * q.x() + q.z() * q.z()).sqrt() + 0.2 * fbm(&q, octaves);
+                let t = (r * rings).fract();
+                blend(light, dark, t)
+            }
+        }
+    }
+}
+
+// How texture coordinates outside [0, 1] are mapped back into the image.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+fn wrap(i: i64, n: usize, mode: WrapMode) -> usize {
+    let n = n as i64;
+    let wrapped = match mode {
+        WrapMode::Repeat => i.rem_euclid(n),
+        WrapMode::Clamp => i.clamp(0, n - 1),
+        WrapMode::Mirror => {
+            let m = i.rem_euclid(2 * n);
+            if m < n {
+                m
+            } else {
+                2 * n - 1 - m
+            }
+        }
+    };
+    wrapped as usize
+}
+
+// How texels are combined when sampling an image. Nearest, the default,
+// looks up a single texel as textures always have. Bilinear and Trilinear
+// use the mip-map level matching the footprint of the ray on the surface.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Bilinear,
+    Trilinear,
+}
+
+pub fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+// Converts an sRGB encoded channel value in [0, 1] to linear intensity.
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Loads a PNG, JPEG, HDR, EXR or TGA image of any bit depth as linear RGBA
+// pixels, top row first. Greyscale images are expanded to RGB and images
+// without alpha are opaque. Integer formats are assumed to be sRGB encoded
+// unless linear is set, as it should be for data such as normal maps; float
+// formats are always linear.
+pub fn load_texture_image(path: &str, linear: bool) -> (Vec<[f32; 4]>, usize, usize) {
+    let image = image::open(path).expect(path);
+    let is_float = matches!(
+        image.color(),
+        image::ColorType::Rgb32F | image::ColorType::Rgba32F
+    );
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels = image
+        .into_rgba32f()
+        .pixels()
+        .map(|p| {
+            let [r, g, b, a] = p.0;
+            if is_float || linear {
+                [r, g, b, a]
+            } else {
+                [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a]
+            }
+        })
+        .collect();
+    (pixels, width, height)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct MipLevel {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 4]>,
+}
+
+fn lerp4(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    [
+        a[0] + t * (b[0] - a[0]),
+        a[1] + t * (b[1] - a[1]),
+        a[2] + t * (b[2] - a[2]),
+        a[3] + t * (b[3] - a[3]),
+    ]
+}
+
+impl MipLevel {
+    // mode gives the wrapping across and up the image.
+    fn texel(&self, x: i64, y: i64, mode: [WrapMode; 2]) -> [f32; 4] {
+        let x = wrap(x, self.width, mode[0]);
+        let y = wrap(y, self.height, mode[1]);
+        self.pixels[y * self.width + x]
+    }
+
+    fn nearest(&self, u: f64, v: f64, mode: [WrapMode; 2]) -> [f32; 4] {
+        let x = (u * self.width as f64).floor() as i64;
+        let y = ((1.0 - v) * self.height as f64).floor() as i64;
+        self.texel(x, y, mode)
+    }
+
+    fn bilinear(&self, u: f64, v: f64, mode: [WrapMode; 2]) -> [f32; 4] {
+        let s = u * self.width as f64 - 0.5;
+        let t = (1.0 - v) * self.height as f64 - 0.5;
+        let (x, y) = (s.floor() as i64, t.floor() as i64);
+        let (fx, fy) = ((s - s.floor()) as f32, (t - t.floor()) as f32);
+        let top = lerp4(self.texel(x, y, mode), self.texel(x + 1, y, mode), fx);
+        let bottom = lerp4(
+            self.texel(x, y + 1, mode),
+            self.texel(x + 1, y + 1, mode),
+            fx,
+        );
+        lerp4(top, bottom, fy)
+    }
+
+    // Halves the resolution with a 2x2 box filter.
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = vec![[0.0; 4]; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.0; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (2 * x + dx).min(self.width - 1);
+                    let sy = (2 * y + dy).min(self.height - 1);
+                    let p = self.pixels[sy * self.width + sx];
+                    for c in 0..4 {
+                        sum[c] += 0.25 * p[c];
+                    }
+                }
+                pixels[y * width + x] = sum;
+            }
+        }
+        MipLevel {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+// Images loaded by MipMap::load, by path and whether they are linear.
+static TEXTURE_CACHE: SharedCache<(PathBuf, bool), MipMap> = OnceLock::new();
+
+// An RGBA image with a pyramid of successively half resolution copies, used
+// to avoid aliasing when a texture is seen from far away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MipMap {
+    levels: Vec<MipLevel>,
+    has_alpha: bool,
+    // The file the image was loaded from, or empty if it was made in memory.
+    path: String,
+}
+
+impl MipMap {
+    pub fn new(pixels: Vec<[f32; 4]>, width: usize, height: usize) -> MipMap {
+        let has_alpha = pixels.iter().any(|p| p[3] < 1.0);
+        let mut levels = vec![MipLevel {
+            width,
+            height,
+            pixels,
+        }];
+        while let Some(last) = levels.last() {
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
+        }
+        MipMap {
+            levels,
+            has_alpha,
+            path: String::new(),
+        }
+    }
+
+    // Loads an image file, sharing it with any other texture that is still
+    // using the same file.
+    pub fn load(path: &str, linear: bool) -> Arc<MipMap> {
+        load_shared(&TEXTURE_CACHE, (file_key(path), linear), || {
+            let (pixels, width, height) = load_texture_image(path, linear);
+            MipMap {
+                path: path.to_string(),
+                ..MipMap::new(pixels, width, height)
+            }
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Whether any pixel of the image is not fully opaque.
+    pub fn has_alpha(&self) -> bool {
+        self.has_alpha
+    }
+
+    // Samples the image at (u, v), where v = 0 is the bottom row. footprint
+    // is the approximate width of the area seen by the ray in texture
+    // coordinates, and selects the mip-map level.
+    pub fn sample_rgba(
+        &self,
+        u: f64,
+        v: f64,
+        footprint: f64,
+        filter: Filter,
+        mode: [WrapMode; 2],
+    ) -> [f32; 4] {
+        let size = self.width().max(self.height()) as f64;
+        let max_level = (self.levels.len() - 1) as f64;
+        let lod = (footprint * size).log2().clamp(0.0, max_level);
+        match filter {
+            Filter::Nearest => self.levels[0].nearest(u, v, mode),
+            Filter::Bilinear => self.levels[lod.round() as usize].bilinear(u, v, mode),
+            Filter::Trilinear => {
+                let a = self.levels[lod.floor() as usize].bilinear(u, v, mode);
+                let b = self.levels[lod.ceil() as usize].bilinear(u, v, mode);
+                lerp4(a, b, (lod - lod.floor()) as f32)
+            }
+        }
+    }
+
+    pub fn sample(&self, u: f64, v: f64, footprint: f64, filter: Filter, mode: WrapMode) -> Srgb {
+        let [r, g, b, _] = self.sample_rgba(u, v, footprint, filter, [mode; 2]);
+        Srgb::new(r, g, b)
+    }
+}
+
+// An image texture loaded from a file, rotated horizontally by h_offset.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "ImageTextureParams")]
+pub struct ImageTexture {
+    pub image: String,
+    pub h_offset: f64,
+    #[serde(skip_serializing_if = "is_default")]
+    pub filter: Filter,
+    #[serde(skip_serializing_if = "is_default")]
+    pub wrap: WrapMode,
+    #[serde(skip_serializing_if = "is_default")]
+    pub linear: bool,
+    #[serde(skip_serializing)]
+    pixels: Arc<MipMap>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageTextureParams {
+    pub image: String,
+    #[serde(default)]
+    pub h_offset: f64,
+    #[serde(default)]
+    pub filter: Filter,
+    #[serde(default)]
+    pub wrap: WrapMode,
+    // Set for images holding data rather than colors, such as normal maps,
+    // so that they are not converted from sRGB.
+    #[serde(default)]
+    pub linear: bool,
+}
+
+impl From<ImageTextureParams> for ImageTexture {
+    fn from(p: ImageTextureParams) -> Self {
+        ImageTexture {
+            pixels: MipMap::load(&p.image, p.linear),
+            image: p.image,
+            h_offset: p.h_offset,
+            filter: p.filter,
+            wrap: p.wrap,
+            linear: p.linear,
+        }
+    }
+}
+
+impl ImageTexture {
+    pub fn new(path: &str, h_offset: f64) -> ImageTexture {
+        ImageTexture {
+            image: path.to_string(),
+            h_offset,
+            filter: Filter::default(),
+            wrap: WrapMode::default(),
+            linear: false,
+            pixels: MipMap::load(path, false),
+        }
+    }
+
+    pub fn value(&self, u: f64, v: f64, footprint: f64) -> Srgb {
+        self.pixels
+            .sample(u + self.h_offset, v, footprint, self.filter, self.wrap)
+    }
+
+    // The alpha channel if the image has one, else its brightness, so that
+    // either a transparent PNG or a black and white mask can be used.
+    pub fn opacity(&self, u: f64, v: f64, footprint: f64) -> f64 {
+        let [r, g, b, a] =
+            self.pixels
+                .sample_rgba(u + self.h_offset, v, footprint, self.filter, [self.wrap; 2]);
+        if self.pixels.has_alpha() {
+            a as f64
+        } else {
+            luminance(Srgb::new(r, g, b))
+        }
+    }
+}
+
+// A material parameter that may vary over a surface. In JSON this is a
+// number or an [r, g, b] array for a constant, {"image": "path"} for an
+// image texture, or a procedural texture object such as {"Checker": {..}}.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TextureRef {
+    Value(f64),
+    Color(#[serde_as(as = "SrgbAsArray")] Srgb),
+    Image(ImageTexture),
+    Procedural(Procedural),
+}
+
+impl TextureRef {
+    pub fn value(&self, u: f64, v: f64, p: &Point3D, footprint: f64) -> Srgb {
+        match self {
+            TextureRef::Value(x) => Srgb::new(*x as f32, *x as f32, *x as f32),
+            TextureRef::Color(c) => *c,
+            TextureRef::Image(t) => t.value(u, v, footprint),
+            TextureRef::Procedural(t) => t.value(u, v, p),
+        }
+    }
+
+    // The color of the texture where a ray hit a surface.
+    pub fn color(&self, hit_record: &HitRecord) -> Srgb {
+        self.value(
+            hit_record.u,
+            hit_record.v,
+            &hit_record.point,
+            hit_record.footprint,
+        )
+    }
+
+    // The value of a scalar parameter such as roughness or index of
+    // refraction. Colored textures are converted to their luminance.
+    pub fn scalar(&self, hit_record: &HitRecord) -> f64 {
+        match self {
+            TextureRef::Value(x) => *x,
+            _ => luminance(self.color(hit_record)),
+        }
+    }
+
+    // Like scalar, but using the alpha channel of images that have one.
+    pub fn opacity(&self, hit_record: &HitRecord) -> f64 {
+        match self {
+            TextureRef::Image(t) => t.opacity(hit_record.u, hit_record.v, hit_record.footprint),
+            _ => self.scalar(hit_record),
+        }
+    }
+}
+
+pub fn luminance(c: Srgb) -> f64 {
+    (0.2126 * c.red + 0.7152 * c.green + 0.0722 * c.blue) as f64
+}
+
+impl From<Srgb> for TextureRef {
+    fn from(color: Srgb) -> Self {
+        TextureRef::Color(color)
+    }
+}
+
+impl From<f64> for TextureRef {
+    fn from(value: f64) -> Self {
+        TextureRef::Value(value)
+    }
+}
+
+// A TextureRef as written in a scene file, with image parameters not yet
+// loaded, so that data textures can be loaded without sRGB decoding.
+#[serde_with::serde_as]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DataTextureParams {
+    Value(f64),
+    Color(#[serde_as(as = "SrgbAsArray")] Srgb),
+    Image(ImageTextureParams),
+    Procedural(Procedural),
+}
+
+// Reads a texture holding data rather than colors, such as a normal or
+// height map, whose images are always linear whatever the scene says.
+fn deserialize_data_texture<'de, D: Deserializer<'de>>(d: D) -> Result<TextureRef, D::Error> {
+    Ok(match DataTextureParams::deserialize(d)? {
+        DataTextureParams::Value(x) => TextureRef::Value(x),
+        DataTextureParams::Color(c) => TextureRef::Color(c),
+        DataTextureParams::Image(p) => {
+            TextureRef::Image(ImageTexture::from(ImageTextureParams { linear: true, ..p }))
+        }
+        DataTextureParams::Procedural(t) => TextureRef::Procedural(t),
+    })
+}
+
+// Distance in texture space, and along the surface, between the samples
+// used to estimate the slope of a bump map.
+const BUMP_DELTA: f64 = 1e-3;
+
+// Adds surface detail by changing the shading normal without changing the
+// geometry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum NormalMap {
+    // A tangent space normal map, where red, green and blue in [0, 1] encode
+    // the normal along the tangent, bitangent and normal in [-1, 1].
+    // strength scales the tangential part. Images are read as linear.
+    Tangent {
+        #[serde(deserialize_with = "deserialize_data_texture")]
+        texture: TextureRef,
+        strength: f64,
+    },
+    // A height map. The normal tilts away from higher areas, scaled by
+    // strength. Images are read as linear.
+    Bump {
+        #[serde(deserialize_with = "deserialize_data_texture")]
+        height: TextureRef,
+        strength: f64,
+    },
+}
+
+impl NormalMap {
+    pub fn perturb(&self, hit_record: &mut HitRecord) {
+        let (t, b, n) = (hit_record.tangent, hit_record.bitangent, hit_record.normal);
+        let perturbed = match self {
+            NormalMap::Tangent { texture, strength } => {
+                let c = texture.color(hit_record);
+                let x = (2.0 * c.red as f64 - 1.0) * strength;
+                let y = (2.0 * c.green as f64 - 1.0) * strength;
+                let z = 2.0 * c.blue as f64 - 1.0;
+                t * x + b * y + n * z
+            }
+            NormalMap::Bump { height, strength } => {
+                let (u, v, p, footprint) = (
+                    hit_record.u,
+                    hit_record.v,
+                    &hit_record.point,
+                    hit_record.footprint,
+                );
+                // Step along the surface as well as in (u, v), so that solid
+                // textures, which only look at the point, also have a slope.
+                let h = |du: f64, dv: f64| {
+                    let p = *p + t * du + b * dv;
+                    luminance(height.value(u + du, v + dv, &p, footprint))
+                };
+                let base = h(0.0, 0.0);
+                let dhdu = (h(BUMP_DELTA, 0.0) - base) / BUMP_DELTA;
+                let dhdv = (h(0.0, BUMP_DELTA) - base) / BUMP_DELTA;
+                n - (t * dhdu + b * dhdv) * *strength
+            }
+        };
+        // Never tilt the shading normal past the surface.
+        if perturbed.dot(&n) > 0.0 {
+            hit_record.normal = perturbed.unit_vector();
+        }
+    }
+}
+
+#[test]
+fn test_perlin() {
+    // Noise is zero on the integer lattice and bounded elsewhere.
+    assert_approx_eq!(perlin(&Point3D::new(1.0, 2.0, 3.0)), 0.0);
+    for i in 0..100 {
+        let p = Point3D::new(i as f64 * 0.37, i as f64 * 0.11, i as f64 * 0.73);
+        let n = perlin(&p);
+        assert!((-1.0..=1.0).contains(&n));
+        assert_eq!(n, perlin(&p));
+    }
+}
+
+#[test]
+fn test_checker() {
+    let black = Srgb::new(0.0, 0.0, 0.0);
+    let white = Srgb::new(1.0, 1.0, 1.0);
+    let checker = Procedural::Checker {
+        even: black,
+        odd: white,
+        scale: 2.0,
+    };
+    let p = Point3D::new(0.0, 0.0, 0.0);
+    assert_eq!(checker.value(0.25, 0.25, &p), black);
+    assert_eq!(checker.value(0.75, 0.25, &p), white);
+    assert_eq!(checker.value(0.75, 0.75, &p), black);
+
+    let solid = Procedural::SolidChecker {
+        even: black,
+        odd: white,
+        scale: 1.0,
+    };
+    assert_eq!(solid.value(0.0, 0.0, &Point3D::new(0.5, 0.5, 0.5)), black);
+    assert_eq!(solid.value(0.0, 0.0, &Point3D::new(-0.5, 0.5, 0.5)), white);
+}
+
+#[test]
+fn test_texture_ref_to_json() {
+    let color = TextureRef::from(Srgb::new(0.5, 0.5, 0.5));
+    assert_eq!("[0.5,0.5,0.5]", serde_json::to_string(&color).unwrap());
+    let value = TextureRef::from(0.25);
+    assert_eq!("0.25", serde_json::to_string(&value).unwrap());
+    let p = Point3D::new(0.0, 0.0, 0.0);
+    assert_eq!(value.value(0.0, 0.0, &p, 0.0), Srgb::new(0.25, 0.25, 0.25));
+
+    let json = r#"{"Marble":{"base":[0.9,0.9,0.9],"vein":[0.2,0.2,0.3],"scale":4.0,"turbulence":10.0,"octaves":7}}"#;
+    let marble = serde_json::from_str::<TextureRef>(json).unwrap();
+    assert!(matches!(
+        marble,
+        TextureRef::Procedural(Procedural::Marble { octaves: 7, .. })
+    ));
+    assert_eq!(json, serde_json::to_string(&marble).unwrap());
+
+    let json = r#"{"image":"data/moon.jpg","h_offset":0.5}"#;
+    let moon = serde_json::from_str::<TextureRef>(json).unwrap();
+    match moon {
+        TextureRef::Image(ref t) => {
+            assert_eq!((t.pixels.width(), t.pixels.height()), (2048, 1024));
+            assert_approx_eq!(t.h_offset, 0.5);
+        }
+        _ => panic!("Wrong texture type"),
+    }
+    assert_eq!(json, serde_json::to_string(&moon).unwrap());
+    let c = moon.value(0.5, 0.5, &p, 0.0);
+    assert!(c.red > 0.0);
+}
+
+#[test]
+fn test_wrap() {
+    assert_eq!(wrap(5, 4, WrapMode::Repeat), 1);
+    assert_eq!(wrap(-1, 4, WrapMode::Repeat), 3);
+    assert_eq!(wrap(5, 4, WrapMode::Clamp), 3);
+    assert_eq!(wrap(-1, 4, WrapMode::Clamp), 0);
+    assert_eq!(wrap(4, 4, WrapMode::Mirror), 3);
+    assert_eq!(wrap(-1, 4, WrapMode::Mirror), 0);
+    assert_eq!(wrap(9, 4, WrapMode::Mirror), 1);
+}
+
+#[test]
+fn test_mipmap() {
+    // A 2x2 image of black and white texels averages to grey at level 1.
+    let (black, white) = ([0.0, 0.0, 0.0, 1.0], [1.0, 1.0, 1.0, 1.0]);
+    let image = MipMap::new(vec![black, white, white, black], 2, 2);
+    assert_eq!(image.levels(), 2);
+    let black = image.sample(0.25, 0.75, 0.0, Filter::Nearest, WrapMode::Repeat);
+    assert_eq!(black, Srgb::new(0.0, 0.0, 0.0));
+    let white = image.sample(0.75, 0.75, 0.0, Filter::Bilinear, WrapMode::Repeat);
+    assert_eq!(white, Srgb::new(1.0, 1.0, 1.0));
+    // Halfway between texel centres, bilinear filtering blends them.
+    let edge = image.sample(0.5, 0.75, 0.0, Filter::Bilinear, WrapMode::Clamp);
+    assert_approx_eq!(edge.red, 0.5);
+    // A footprint covering the whole image selects the coarsest level.
+    let far = image.sample(0.25, 0.75, 1.0, Filter::Trilinear, WrapMode::Repeat);
+    assert_approx_eq!(far.red, 0.5);
+}
+
+#[test]
+fn test_wrap_axes() {
+    // Textures look up single texels unless a scene asks for filtering.
+    assert_eq!(Filter::default(), Filter::Nearest);
+    // White above black. Wrapping up the image blends the top edge with the
+    // bottom row, clamping does not.
+    let image = MipMap::new(vec![[1.0; 4], [0.0, 0.0, 0.0, 1.0]], 1, 2);
+    let repeat = image.sample_rgba(0.5, 1.0, 0.0, Filter::Bilinear, [WrapMode::Repeat; 2]);
+    assert_approx_eq!(repeat[0], 0.5);
+    let clamp = [WrapMode::Repeat, WrapMode::Clamp];
+    let top = image.sample_rgba(0.5, 1.0, 0.0, Filter::Bilinear, clamp);
+    assert_eq!(top, [1.0; 4]);
+}
+
+#[test]
+fn test_load_texture_formats() {
+    // 16 bit greyscale PNG, converted from sRGB and expanded to RGB.
+    let grey = image::ImageBuffer::from_pixel(4, 2, image::Luma([32768u16]));
+    let grey_path = temp_path("test_texture_grey16.png");
+    grey.save(&grey_path).unwrap();
+    let (pixels, width, height) = load_texture_image(&grey_path, false);
+    assert_eq!((width, height, pixels.len()), (4, 2, 8));
+    let expected = srgb_to_linear(32768.0 / 65535.0);
+    assert_approx_eq!(pixels[0][0], expected);
+    assert_approx_eq!(pixels[0][2], expected);
+    assert_eq!(pixels[0][3], 1.0);
+
+    // 8 bit PNG with alpha, loaded as linear data.
+    let rgba = image::ImageBuffer::from_pixel(2, 2, image::Rgba([255u8, 0, 51, 0]));
+    let rgba_path = temp_path("test_texture_rgba.png");
+    rgba.save(&rgba_path).unwrap();
+    let (pixels, _, _) = load_texture_image(&rgba_path, true);
+    assert_eq!(pixels[3], [1.0, 0.0, 0.2, 0.0]);
+
+    // Radiance HDR keeps values above 1.
+    let hdr_path = temp_path("test_texture.hdr");
+    let file = std::fs::File::create(&hdr_path).unwrap();
+    image::codecs::hdr::HdrEncoder::new(file)
+        .encode(&[image::Rgb([4.0f32, 0.5, 0.25]); 4], 2, 2)
+        .unwrap();
+    let (pixels, _, _) = load_texture_image(&hdr_path, false);
+    assert_approx_eq!(pixels[0][0], 4.0, 0.05);
+    assert_approx_eq!(pixels[0][1], 0.5, 0.01);
+
+    // OpenEXR float images are linear, including alpha.
+    let exr = image::ImageBuffer::from_pixel(2, 2, image::Rgba([8.0f32, 0.5, 0.25, 0.5]));
+    let exr_path = temp_path("test_texture.exr");
+    exr.save(&exr_path).unwrap();
+    let (pixels, _, _) = load_texture_image(&exr_path, false);
+    assert_eq!(pixels[0], [8.0, 0.5, 0.25, 0.5]);
+
+    // TGA, as used by many game assets.
+    let tga = image::ImageBuffer::from_pixel(2, 2, image::Rgb([255u8, 255, 255]));
+    let tga_path = temp_path("test_texture.tga");
+    tga.save(&tga_path).unwrap();
+    let (pixels, _, _) = load_texture_image(&tga_path, false);
+    assert_eq!(pixels[0], [1.0, 1.0, 1.0, 1.0]);
+}
+
+#[test]
+fn test_texture_cache() {
+    let a = MipMap::load("data/moon.jpg", false);
+    let b = MipMap::load("./data/moon.jpg", false);
+    assert!(Arc::ptr_eq(&a, &b));
+    // Linear and sRGB decodings of the same file are different images.
+    let c = MipMap::load("data/moon.jpg", true);
+    assert!(!Arc::ptr_eq(&a, &c));
+}